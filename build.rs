@@ -1,4 +1,11 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// used when `KERNEL_CMDLINE` isn't set, see kernel/src/cmdline.rs for the format
+const DEFAULT_CMDLINE: &str = "log=debug";
+
+// everything in this directory ends up in the kernel's initrd
+const RAMDISK_DIR: &str = "ramdisk";
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -7,19 +14,114 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // the ramdisk is a cpio archive of RAMDISK_DIR plus the kernel command line as `cmdline`,
+    // so changing either only rebuilds the disk images and not the kernel itself
+    println!("cargo:rerun-if-env-changed=KERNEL_CMDLINE");
+    println!("cargo:rerun-if-changed={RAMDISK_DIR}");
+    let cmdline = std::env::var("KERNEL_CMDLINE").unwrap_or_else(|_| DEFAULT_CMDLINE.to_string());
+
+    let mut archive = Cpio::default();
+    archive.file("cmdline", cmdline.as_bytes());
+    if Path::new(RAMDISK_DIR).is_dir() {
+        archive.directory_contents(Path::new(RAMDISK_DIR), "");
+    }
+    let ramdisk_path = out_dir.join("ramdisk.cpio");
+    fs::write(&ramdisk_path, archive.finish()).unwrap();
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
     // pass the disk image paths as env variables to the
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
+    // the kernel ELF itself, used for the symbols in the runner's gdb script
+    println!("cargo:rustc-env=KERNEL_PATH={}", kernel.display());
+}
+
+/// Writer for the cpio "newc" format, which is what kernel/src/initrd.rs reads.
+#[derive(Default)]
+struct Cpio {
+    data: Vec<u8>,
+    inode: u32,
+}
+
+impl Cpio {
+    const MODE_DIR: u32 = 0o040755;
+    const MODE_FILE: u32 = 0o100644;
+
+    fn file(&mut self, name: &str, contents: &[u8]) {
+        self.entry(name, Self::MODE_FILE, contents);
+    }
+
+    /// Adds everything below `dir`, with names relative to it and prefixed by `prefix`.
+    fn directory_contents(&mut self, dir: &Path, prefix: &str) {
+        let mut entries: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        // keep the archive reproducible
+        entries.sort();
+
+        for path in entries {
+            let name = format!("{prefix}{}", path.file_name().unwrap().to_str().unwrap());
+            if path.is_dir() {
+                self.entry(&name, Self::MODE_DIR, &[]);
+                self.directory_contents(&path, &format!("{name}/"));
+            } else {
+                self.file(&name, &fs::read(&path).unwrap());
+            }
+        }
+    }
+
+    fn entry(&mut self, name: &str, mode: u32, contents: &[u8]) {
+        self.inode += 1;
+        let fields = [
+            self.inode,
+            mode,
+            0, // uid
+            0, // gid
+            1, // nlink
+            0, // mtime
+            contents.len() as u32,
+            0, // devmajor
+            0, // devminor
+            0, // rdevmajor
+            0, // rdevminor
+            name.len() as u32 + 1,
+            0, // check
+        ];
+
+        self.data.extend_from_slice(b"070701");
+        for field in fields {
+            self.data
+                .extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.align();
+        self.data.extend_from_slice(contents);
+        self.align();
+    }
+
+    fn align(&mut self) {
+        while !self.data.len().is_multiple_of(4) {
+            self.data.push(0);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.entry("TRAILER!!!", 0, &[]);
+        self.data
+    }
 }
@@ -0,0 +1,183 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::str::FromStr;
+
+use acpi::aml::AmlError;
+use acpi::aml::Interpreter;
+use acpi::aml::namespace::{AmlName, NamespaceLevelKind};
+use acpi::aml::object::Object;
+use acpi::aml::resource::{self, AddressSpaceResourceType, Resource};
+use log::{debug, info, warn};
+
+use crate::acpi::init::{AcpiHandler, acpi_platform};
+
+/// PCI and PCI Express root bridges, as compressed EISA IDs.
+const PCI_ROOT_BRIDGE_IDS: [u32; 2] = [eisa_id(b"PNP0A03"), eisa_id(b"PNP0A08")];
+
+static mut INTERPRETER: Option<Interpreter<AcpiHandler>> = None;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowKind {
+    Memory,
+    Io,
+}
+
+/// An address range a root bridge forwards to the devices behind it.
+#[derive(Debug, Clone)]
+pub struct PciWindow {
+    pub kind: WindowKind,
+    pub range: Range<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PciRootBridge {
+    pub segment: u16,
+    pub bus: u8,
+    pub windows: Vec<PciWindow>,
+}
+
+const fn eisa_letter(c: u8) -> u32 {
+    ((c - b'@') & 0x1F) as u32
+}
+
+const fn eisa_hex(c: u8) -> u32 {
+    (if c <= b'9' { c - b'0' } else { c - b'A' + 10 }) as u32
+}
+
+/// Compresses an ID like `PNP0A03` the way `_HID` integers are encoded.
+const fn eisa_id(id: &[u8; 7]) -> u32 {
+    let id = eisa_letter(id[0]) << 26
+        | eisa_letter(id[1]) << 21
+        | eisa_letter(id[2]) << 16
+        | eisa_hex(id[3]) << 12
+        | eisa_hex(id[4]) << 8
+        | eisa_hex(id[5]) << 4
+        | eisa_hex(id[6]);
+    id.swap_bytes()
+}
+
+/// Loads the DSDT and SSDTs and initializes the namespace.
+///
+/// PCI configuration space is used by a lot of AML, so [`crate::acpi::pcie::init`] should run
+/// first.
+pub fn init() {
+    let interpreter = match Interpreter::new_from_platform(unsafe { acpi_platform() }) {
+        Ok(interpreter) => interpreter,
+        Err(error) => {
+            warn!("Failed to load AML: {:?}", error);
+            return;
+        }
+    };
+    interpreter.initialize_namespace();
+
+    unsafe {
+        INTERPRETER = Some(interpreter);
+    }
+    info!("AML namespace loaded");
+}
+
+fn interpreter() -> Option<&'static Interpreter<AcpiHandler>> {
+    unsafe { INTERPRETER.as_ref() }
+}
+
+/// Evaluates `name` in the scope of the device at `path`, if it exists.
+fn evaluate_in(
+    interpreter: &Interpreter<AcpiHandler>,
+    path: &AmlName,
+    name: &str,
+) -> Result<Option<Object>, AmlError> {
+    let object =
+        interpreter.evaluate_if_present(AmlName::from_str(name)?.resolve(path)?, vec![])?;
+    Ok(object.map(|object| (*object).clone()))
+}
+
+fn is_pci_root_bridge(interpreter: &Interpreter<AcpiHandler>, path: &AmlName) -> bool {
+    match evaluate_in(interpreter, path, "_HID") {
+        Ok(Some(Object::Integer(id))) => PCI_ROOT_BRIDGE_IDS.contains(&(id as u32)),
+        Ok(Some(Object::String(id))) => id == "PNP0A03" || id == "PNP0A08",
+        _ => false,
+    }
+}
+
+/// The PCI root bridges in the namespace and the resource windows their `_CRS` describes.
+///
+/// Empty if [`init`] didn't run or failed.
+pub fn pci_root_bridges() -> Vec<PciRootBridge> {
+    let Some(interpreter) = interpreter() else {
+        return Vec::new();
+    };
+
+    let mut devices = Vec::new();
+    let traversal = interpreter.namespace.lock().traverse(|path, level| {
+        if level.kind == NamespaceLevelKind::Device {
+            devices.push(path.clone());
+        }
+        Ok(true)
+    });
+    if let Err(error) = traversal {
+        warn!("Failed to traverse the AML namespace: {:?}", error);
+    }
+
+    devices
+        .iter()
+        .filter(|path| is_pci_root_bridge(interpreter, path))
+        .filter_map(|path| match root_bridge(interpreter, path) {
+            Ok(bridge) => Some(bridge),
+            Err(error) => {
+                warn!(
+                    "Failed to read the resources of PCI root bridge {}: {:?}",
+                    path, error
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+fn root_bridge(
+    interpreter: &Interpreter<AcpiHandler>,
+    path: &AmlName,
+) -> Result<PciRootBridge, AmlError> {
+    // both default to 0 when they're missing
+    let segment = match evaluate_in(interpreter, path, "_SEG")? {
+        Some(Object::Integer(segment)) => segment as u16,
+        _ => 0,
+    };
+    let bus = match evaluate_in(interpreter, path, "_BBN")? {
+        Some(Object::Integer(bus)) => bus as u8,
+        _ => 0,
+    };
+
+    let crs = interpreter.evaluate(AmlName::from_str("_CRS")?.resolve(path)?, vec![])?;
+    let windows = resource::resource_descriptor_list(crs)?
+        .into_iter()
+        .filter_map(|resource| match resource {
+            Resource::AddressSpace(descriptor) if descriptor.length != 0 => {
+                let kind = match descriptor.resource_type {
+                    AddressSpaceResourceType::MemoryRange => WindowKind::Memory,
+                    AddressSpaceResourceType::IORange => WindowKind::Io,
+                    AddressSpaceResourceType::BusNumberRange => return None,
+                };
+                // the translation offset is only used on architectures with separate bus
+                // address spaces, on x86 CPU and bus addresses are the same
+                let start = descriptor.address_range.0;
+                Some(PciWindow {
+                    kind,
+                    range: start..start + descriptor.length,
+                })
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    debug!(
+        "PCI root bridge {} ({:04x}:{:02x}): {:x?}",
+        path, segment, bus, windows
+    );
+    Ok(PciRootBridge {
+        segment,
+        bus,
+        windows,
+    })
+}
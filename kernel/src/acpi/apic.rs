@@ -1,19 +1,35 @@
+pub mod timer;
+
 use core::intrinsics::{volatile_load, volatile_store};
 
 use log::info;
+use x86_64::registers::model_specific::{ApicBase, Msr};
 
 use crate::{
-    memory::{self, ERROR_ADDRESS},
+    cmdline, memory,
     support::{CPU_FLAGS, CPUFlags},
 };
 
-static mut APIC_BASE: u64 = ERROR_ADDRESS;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+// x2APIC registers are MSRs starting here, one for every 16 bytes of the xAPIC MMIO page
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const REG_ID: u64 = 0x20;
+const REG_EOI: u64 = 0xB0;
+const REG_SPURIOUS: u64 = 0xF0;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+static mut X2APIC: bool = false;
 
 fn get_base() -> u64 {
-    unsafe { x86_64::registers::model_specific::ApicBase::MSR.read() }
+    unsafe { ApicBase::MSR.read() }
 }
 
-#[allow(const_item_mutation)]
 pub fn init() {
     if !CPU_FLAGS.contains(CPUFlags::APIC) {
         panic!("APIC is not available");
@@ -21,32 +37,106 @@ pub fn init() {
 
     unsafe {
         info!("Apic Base: {:064b}", get_base());
-        x86_64::registers::model_specific::ApicBase::MSR.write(get_base() | 0x100);
-        APIC_BASE = get_base();
-        memory::map_identity(APIC_BASE..(APIC_BASE + 0x03F0));
+        // `x2apic=off` forces the MMIO interface, e.g. to test it on a CPU with x2APIC
+        X2APIC = CPU_FLAGS.contains(CPUFlags::x2APIC) && cmdline::enabled("x2apic");
+    }
+    enable();
+    info!(
+        "APIC initialized in {} mode",
+        if is_x2apic() { "x2APIC" } else { "xAPIC" }
+    );
+}
+
+/// Enables the current CPU's Local APIC in the mode [`init`] picked.
+///
+/// In xAPIC mode its registers are mapped and their address kept in the CPU's
+/// [`crate::percpu`] data.
+pub fn enable() {
+    unsafe {
+        if !X2APIC {
+            // the low 12 bits are flags (BSP, x2APIC and global enable)
+            let base = get_base() & !0xFFF;
+            memory::map_identity(base..(base + 0x03F0));
+            percpu!(apic_base).set(base);
+        }
 
-        write_reg(0xF0, read_reg(0xF0) | 0x100);
+        let mut flags = APIC_BASE_ENABLE;
+        if X2APIC {
+            flags |= APIC_BASE_X2APIC;
+        }
+        // xAPIC has to be enabled before switching to x2APIC
+        let mut msr = ApicBase::MSR;
+        msr.write(get_base() | APIC_BASE_ENABLE);
+        msr.write(get_base() | flags);
+
+        write_reg(REG_SPURIOUS, read_reg(REG_SPURIOUS) | 0x100);
     }
-    info!("APIC initialized");
+}
+
+pub fn is_x2apic() -> bool {
+    unsafe { X2APIC }
 }
 
 #[allow(dead_code)]
 pub(super) unsafe fn acpi_reg_addr(offset: u64) -> u64 {
-    unsafe { APIC_BASE + offset }
+    percpu!(apic_base).get() + offset
 }
 
+fn x2apic_msr(offset: u64) -> Msr {
+    Msr::new(X2APIC_MSR_BASE + (offset >> 4) as u32)
+}
+
+/// Writes the register at `offset` in the xAPIC MMIO page, or its MSR in x2APIC mode.
 unsafe fn write_reg(offset: u64, data: u32) {
     unsafe {
-        volatile_store((APIC_BASE + offset) as *mut u32, data);
+        if X2APIC {
+            x2apic_msr(offset).write(data as u64);
+        } else {
+            volatile_store((percpu!(apic_base).get() + offset) as *mut u32, data);
+        }
     }
 }
 
 unsafe fn read_reg(offset: u64) -> u32 {
-    unsafe { volatile_load((APIC_BASE + offset) as *mut u32) }
+    unsafe {
+        if X2APIC {
+            x2apic_msr(offset).read() as u32
+        } else {
+            volatile_load((percpu!(apic_base).get() + offset) as *mut u32)
+        }
+    }
+}
+
+/// The ID of the current CPU's Local APIC.
+pub fn id() -> u32 {
+    unsafe {
+        if X2APIC {
+            read_reg(REG_ID)
+        } else {
+            read_reg(REG_ID) >> 24
+        }
+    }
+}
+
+/// Sends an IPI to the Local APIC with ID `apic_id`. `command` is the low half of the ICR
+/// (vector, delivery mode, level, trigger mode and destination shorthand).
+pub fn send_ipi(apic_id: u32, command: u32) {
+    unsafe {
+        if X2APIC {
+            // a single 64 bit register with the full 32 bit destination
+            x2apic_msr(REG_ICR_LOW).write(((apic_id as u64) << 32) | command as u64);
+        } else {
+            write_reg(REG_ICR_HIGH, apic_id << 24);
+            write_reg(REG_ICR_LOW, command);
+            while read_reg(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
 }
 
 pub fn write_eoi() {
     unsafe {
-        write_reg(0xB0, 0);
+        write_reg(REG_EOI, 0);
     }
 }
@@ -0,0 +1,150 @@
+use core::arch::x86_64::_rdtsc;
+
+use log::info;
+use x86_64::registers::model_specific::Msr;
+
+use super::{read_reg, write_reg};
+use crate::interrupts::irq::{self, IrqReturn};
+use crate::support::{CPU_FLAGS, CPUFlags};
+use crate::time;
+
+/// Vector the Local APIC timer fires on. [`init`] installs a handler counting the ticks in
+/// [`crate::percpu::CpuStats`].
+pub const TIMER_VECTOR: u8 = 0xEF;
+
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_INITIAL_COUNT: u64 = 0x380;
+const REG_CURRENT_COUNT: u64 = 0x390;
+const REG_DIVIDE_CONFIG: u64 = 0x3E0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_ONE_SHOT: u32 = 0b00 << 17;
+const LVT_PERIODIC: u32 = 0b01 << 17;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+
+const DIVIDE_BY_16: u32 = 0b0011;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+const CALIBRATION_MS: u64 = 10;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+    TscDeadline,
+}
+
+// Calibrated once on the BSP, every Local APIC is assumed to run at the same rate.
+static mut TIMER_FREQUENCY: u64 = 0;
+
+/// Sets up the timer of the current CPU's Local APIC, masked until it is armed.
+///
+/// The first call calibrates the timer against the clock, so [`time::init`] has to run first,
+/// and installs the handler for [`TIMER_VECTOR`].
+pub fn init() {
+    unsafe {
+        write_reg(REG_DIVIDE_CONFIG, DIVIDE_BY_16);
+        write_reg(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+
+        if TIMER_FREQUENCY == 0 {
+            calibrate();
+            irq::request(
+                TIMER_VECTOR,
+                "apic timer",
+                false,
+                handle_tick,
+                core::ptr::null_mut(),
+            )
+            .expect("Local APIC timer vector already in use")
+            .leak();
+        }
+    }
+}
+
+fn handle_tick(_context: *mut ()) -> IrqReturn {
+    let ticks = percpu!(stats.timer_ticks);
+    ticks.set(ticks.get() + 1);
+    IrqReturn::Handled
+}
+
+unsafe fn calibrate() {
+    unsafe {
+        write_reg(REG_INITIAL_COUNT, u32::MAX);
+        time::stall(CALIBRATION_MS * 1000);
+        let ticks = u32::MAX - read_reg(REG_CURRENT_COUNT);
+        write_reg(REG_INITIAL_COUNT, 0);
+
+        TIMER_FREQUENCY = ticks as u64 * 1000 / CALIBRATION_MS;
+        info!("Calibrated Local APIC timer: {} Hz", TIMER_FREQUENCY);
+    }
+}
+
+pub fn frequency() -> u64 {
+    unsafe { TIMER_FREQUENCY }
+}
+
+pub fn supports_tsc_deadline() -> bool {
+    CPU_FLAGS.contains(CPUFlags::TSC_DEADLINE)
+}
+
+fn ticks(nanos: u64) -> u32 {
+    let ticks = nanos as u128 * frequency() as u128 / NANOS_PER_SEC;
+    ticks.clamp(1, u32::MAX as u128) as u32
+}
+
+/// Fires [`TIMER_VECTOR`] on the current CPU every `period_ns` nanoseconds.
+pub fn start_periodic(period_ns: u64) {
+    unsafe {
+        write_reg(REG_LVT_TIMER, LVT_PERIODIC | TIMER_VECTOR as u32);
+        write_reg(REG_INITIAL_COUNT, ticks(period_ns));
+    }
+}
+
+/// Fires [`TIMER_VECTOR`] on the current CPU once, `delay_ns` nanoseconds from now.
+///
+/// Uses the TSC deadline if the CPU supports it, since that doesn't run out after a few seconds
+/// like the 32 bit counter does.
+pub fn arm_oneshot(delay_ns: u64) {
+    if supports_tsc_deadline() {
+        arm(TimerMode::TscDeadline, delay_ns);
+    } else {
+        arm(TimerMode::OneShot, delay_ns);
+    }
+}
+
+/// Arms the current CPU's timer in `mode`, `delay_ns` is the period for [`TimerMode::Periodic`].
+pub fn arm(mode: TimerMode, delay_ns: u64) {
+    match mode {
+        TimerMode::Periodic => start_periodic(delay_ns),
+        TimerMode::OneShot => unsafe {
+            write_reg(REG_LVT_TIMER, LVT_ONE_SHOT | TIMER_VECTOR as u32);
+            write_reg(REG_INITIAL_COUNT, ticks(delay_ns));
+        },
+        TimerMode::TscDeadline => {
+            assert!(
+                supports_tsc_deadline(),
+                "TSC deadline mode is not supported"
+            );
+            let delta = delay_ns as u128 * time::tsc_frequency() as u128 / NANOS_PER_SEC;
+            unsafe {
+                write_reg(REG_LVT_TIMER, LVT_TSC_DEADLINE | TIMER_VECTOR as u32);
+                // the LVT write has to land before the deadline is set
+                core::arch::asm!("mfence", options(nostack, preserves_flags));
+                let deadline = _rdtsc().saturating_add(delta as u64).max(1);
+                Msr::new(IA32_TSC_DEADLINE).write(deadline);
+            }
+        }
+    }
+}
+
+/// Stops the current CPU's timer.
+pub fn stop() {
+    unsafe {
+        write_reg(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+        write_reg(REG_INITIAL_COUNT, 0);
+        if supports_tsc_deadline() {
+            Msr::new(IA32_TSC_DEADLINE).write(0);
+        }
+    }
+}
@@ -1,43 +1,311 @@
 use core::intrinsics::{volatile_load, volatile_store};
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use acpi::sdt::hpet::HpetTable;
-use log::info;
+use log::{debug, info};
 
+use crate::acpi::apic;
 use crate::acpi::init::acpi_platform;
+use crate::acpi::ioapic::{self, Polarity, TriggerMode};
 use crate::memory;
 
 use crate::memory::ERROR_ADDRESS;
 
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_MAIN_COUNTER: u64 = 0x0F0;
+
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_64BIT_CAP: u64 = 1 << 5;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_FSB_CAP: u64 = 1 << 15;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+const REGISTER_BLOCK_SIZE: u64 = 0x400;
+
 static mut HPET_BASE: u64 = ERROR_ADDRESS; // this is an error value which *should*
+static mut PERIOD_FS: u64 = 0;
+static mut COUNTER_64BIT: bool = true;
+static mut TIMER_COUNT: usize = 0;
+// a 32 bit main counter is extended to 64 bits by counting its wraparounds here
+static LAST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// There is no HPET table.
+    NotPresent,
+    /// The registers aren't in system memory, but in this ACPI address space.
+    UnsupportedAddressSpace(u8),
+    /// [`init`] wasn't called or failed.
+    NotInitialized,
+    NoSuchTimer(usize),
+    /// The timer can't fire periodically.
+    PeriodicUnsupported(usize),
+    /// The timer can neither use FSB delivery nor any I/O APIC input.
+    NoRoute(usize),
+    /// Both FSB messages and the I/O APIC only have room for an 8 bit destination.
+    ApicIdTooLarge(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+}
 
-pub fn init() {
+/// What a single comparator can do.
+#[derive(Debug, Clone, Copy)]
+pub struct TimerInfo {
+    pub index: usize,
+    pub periodic: bool,
+    pub is_64bit: bool,
+    /// Whether it can deliver its interrupt as a message (FSB, like MSI).
+    pub fsb: bool,
+    /// The I/O APIC inputs it can be routed to, one bit per GSI.
+    pub routes: u32,
+}
+
+unsafe fn read_reg(offset: u64) -> u64 {
+    unsafe { volatile_load((HPET_BASE + offset) as *const u64) }
+}
+
+unsafe fn write_reg(offset: u64, value: u64) {
+    unsafe { volatile_store((HPET_BASE + offset) as *mut u64, value) }
+}
+
+fn timer_config(index: usize) -> u64 {
+    0x100 + 0x20 * index as u64
+}
+
+fn timer_comparator(index: usize) -> u64 {
+    0x108 + 0x20 * index as u64
+}
+
+fn timer_fsb_route(index: usize) -> u64 {
+    0x110 + 0x20 * index as u64
+}
+
+/// Maps the HPET, reads its capabilities and starts the main counter.
+pub fn init() -> Result<(), HpetError> {
     unsafe {
         let (hpet_addr, head) = acpi_platform()
             .tables
             .table_headers()
             .find(|(_physical_address, header)| header.signature == acpi::sdt::Signature::HPET)
-            .expect("HPET not supported");
+            .ok_or(HpetError::NotPresent)?;
         memory::map_identity(hpet_addr as u64..hpet_addr as u64 + head.length as u64);
         let hpet = &*(hpet_addr as *const HpetTable);
-        info!("HPET Table: {:#?}", hpet);
+        debug!("HPET Table: {:#?}", hpet);
 
         if hpet.base_address.address_space != 0 {
-            unimplemented!(
-                "HPET registers not mapped to system memory. This is currently not implemented."
-            )
+            return Err(HpetError::UnsupportedAddressSpace(
+                hpet.base_address.address_space,
+            ));
         }
 
         HPET_BASE = hpet.base_address.address;
-        memory::map_identity(HPET_BASE..HPET_BASE + 0xFF);
+        memory::map_identity(HPET_BASE..HPET_BASE + REGISTER_BLOCK_SIZE);
+
+        let capabilities = read_reg(REG_CAPABILITIES);
+        PERIOD_FS = capabilities >> 32;
+        COUNTER_64BIT = capabilities & CAP_COUNTER_64BIT != 0;
+        TIMER_COUNT = ((capabilities >> 8) & 0x1F) as usize + 1;
+
+        // every comparator stays quiet until it's started
+        for index in 0..TIMER_COUNT {
+            let config = read_reg(timer_config(index));
+            write_reg(
+                timer_config(index),
+                config & !(TIMER_INT_ENABLE | TIMER_FSB_ENABLE),
+            );
+        }
+
+        // the main counter runs without legacy replacement routing, so the PIT and RTC keep
+        // their IRQs
+        write_reg(REG_CONFIG, CONFIG_ENABLE);
+
+        info!(
+            "HPET: {} Hz, {} bit counter, {} timers{}",
+            1_000_000_000_000_000 / PERIOD_FS,
+            if COUNTER_64BIT { 64 } else { 32 },
+            TIMER_COUNT,
+            if capabilities & CAP_LEGACY_ROUTE != 0 {
+                ", legacy capable"
+            } else {
+                ""
+            }
+        );
+        for timer in timers() {
+            debug!("HPET {:?}", timer);
+        }
+    }
+    Ok(())
+}
+
+/// Whether [`init`] found and enabled an HPET.
+pub fn available() -> bool {
+    unsafe { HPET_BASE != ERROR_ADDRESS }
+}
 
-        volatile_store((HPET_BASE + 0x010) as *mut u64, 1 << 1);
+/// The main counter, extended to 64 bits if the hardware only has 32.
+///
+/// A 32 bit counter has to be read at least once per wraparound (about 5 minutes at the
+/// usual 14.3 MHz) for this to stay monotonic.
+pub fn counter() -> u64 {
+    let count = unsafe { read_reg(REG_MAIN_COUNTER) };
+    if unsafe { COUNTER_64BIT } {
+        return count;
+    }
 
-        info!("HPET Current Value: {:?}", poll_hpet());
+    let count = count & 0xFFFF_FFFF;
+    let mut last = LAST_COUNT.load(Ordering::Acquire);
+    loop {
+        let mut extended = (last & !0xFFFF_FFFF) | count;
+        if extended < last {
+            extended += 1 << 32;
+        }
+        match LAST_COUNT.compare_exchange_weak(last, extended, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => return extended,
+            // someone else read a newer value in the meantime
+            Err(newer) if newer >= extended => return newer,
+            Err(newer) => last = newer,
+        }
     }
 }
 
-pub fn poll_hpet() -> u64 {
-    let period = unsafe { volatile_load(HPET_BASE as *const u64) } >> 32;
-    let count = unsafe { volatile_load((HPET_BASE + 0xF0) as *const u64) };
-    count * period
+/// Nanoseconds since the HPET was enabled.
+///
+/// The counter period is in femtoseconds, so the product is done in 128 bits and doesn't
+/// overflow in any realistic uptime.
+pub fn nanos() -> u64 {
+    (counter() as u128 * unsafe { PERIOD_FS } as u128 / FEMTOS_PER_NANO) as u64
+}
+
+fn ticks(nanos: u64) -> u64 {
+    (nanos as u128 * FEMTOS_PER_NANO / unsafe { PERIOD_FS } as u128).max(1) as u64
+}
+
+/// The capabilities of every comparator.
+pub fn timers() -> impl Iterator<Item = TimerInfo> {
+    (0..unsafe { TIMER_COUNT }).map(|index| {
+        let config = unsafe { read_reg(timer_config(index)) };
+        TimerInfo {
+            index,
+            periodic: config & TIMER_PERIODIC_CAP != 0,
+            is_64bit: config & TIMER_64BIT_CAP != 0,
+            fsb: config & TIMER_FSB_CAP != 0,
+            routes: (config >> 32) as u32,
+        }
+    })
+}
+
+/// Fires `vector` on the current CPU after `nanos` nanoseconds, and every `nanos` after that in
+/// [`TimerMode::Periodic`].
+///
+/// The interrupt is delivered as a message if the timer supports it, otherwise through the
+/// highest I/O APIC input it can use (the low ones tend to be taken by ISA devices).
+pub fn start_timer(index: usize, mode: TimerMode, nanos: u64, vector: u8) -> Result<(), HpetError> {
+    if !available() {
+        return Err(HpetError::NotInitialized);
+    }
+    let timer = timers().nth(index).ok_or(HpetError::NoSuchTimer(index))?;
+    if mode == TimerMode::Periodic && !timer.periodic {
+        return Err(HpetError::PeriodicUnsupported(index));
+    }
+    let apic_id = apic::id();
+    if apic_id > 0xFF {
+        return Err(HpetError::ApicIdTooLarge(apic_id));
+    }
+
+    let mut config = unsafe { read_reg(timer_config(index)) };
+    config &= !(TIMER_LEVEL_TRIGGERED
+        | TIMER_INT_ENABLE
+        | TIMER_PERIODIC
+        | TIMER_32BIT_MODE
+        | TIMER_ROUTE_MASK
+        | TIMER_FSB_ENABLE);
+
+    if timer.fsb {
+        // an MSI style message to the current CPU's Local APIC
+        let address = 0xFEE0_0000u64 | ((apic_id as u64) << 12);
+        unsafe { write_reg(timer_fsb_route(index), (address << 32) | vector as u64) };
+        config |= TIMER_FSB_ENABLE;
+    } else {
+        let gsi = timer
+            .routes
+            .checked_ilog2()
+            .ok_or(HpetError::NoRoute(index))?;
+        ioapic::route(
+            gsi,
+            vector,
+            apic_id,
+            Polarity::ActiveHigh,
+            TriggerMode::Edge,
+        )
+        .map_err(|_| HpetError::NoRoute(index))?;
+        config |= (gsi as u64) << TIMER_ROUTE_SHIFT;
+    }
+
+    let delta = ticks(nanos);
+    unsafe {
+        write_reg(timer_config(index), config);
+        let now = read_reg(REG_MAIN_COUNTER);
+        match mode {
+            TimerMode::OneShot => {
+                write_reg(timer_comparator(index), now.wrapping_add(delta));
+            }
+            TimerMode::Periodic => {
+                // with VALUE_SET the first write is the comparator, the second the period
+                write_reg(
+                    timer_config(index),
+                    config | TIMER_PERIODIC | TIMER_VALUE_SET,
+                );
+                write_reg(timer_comparator(index), now.wrapping_add(delta));
+                write_reg(timer_comparator(index), delta);
+                config |= TIMER_PERIODIC;
+            }
+        }
+        write_reg(timer_config(index), config | TIMER_INT_ENABLE);
+    }
+
+    debug!(
+        "HPET timer {} armed ({:?}, {} ns) on vector {:#04x}{}",
+        index,
+        mode,
+        nanos,
+        vector,
+        if timer.fsb { " via FSB" } else { "" }
+    );
+    Ok(())
+}
+
+/// Stops a timer started by [`start_timer`].
+pub fn stop_timer(index: usize) -> Result<(), HpetError> {
+    if !available() {
+        return Err(HpetError::NotInitialized);
+    }
+    if index >= unsafe { TIMER_COUNT } {
+        return Err(HpetError::NoSuchTimer(index));
+    }
+
+    unsafe {
+        let config = read_reg(timer_config(index));
+        write_reg(
+            timer_config(index),
+            config & !(TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_FSB_ENABLE),
+        );
+    }
+    Ok(())
 }
@@ -1,16 +1,28 @@
 use core::{
+    hint::spin_loop,
     intrinsics::{volatile_load, volatile_store},
     ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
 };
 
-use acpi::{Handle, PciAddress, PhysicalMapping, aml::AmlError, platform::AcpiPlatform};
+use acpi::{
+    Handle, PciAddress, PhysicalMapping,
+    aml::AmlError,
+    platform::{AcpiPlatform, InterruptModel, ProcessorInfo},
+};
 use log::{debug, info};
 use x86_64::instructions::port::{PortRead, PortWrite};
 
-use crate::{logger::LoggedAddress, memory};
+use crate::{acpi::pcie, logger::LoggedAddress, memory, time};
 
 static mut PLATFORM: Option<AcpiPlatform<AcpiHandler>> = None;
 
+const MAX_AML_MUTEXES: usize = 256;
+// AML mutexes are only ever created, so a handle is an index in here
+static AML_MUTEXES: [AtomicBool; MAX_AML_MUTEXES] =
+    [const { AtomicBool::new(false) }; MAX_AML_MUTEXES];
+static NEXT_AML_MUTEX: AtomicU32 = AtomicU32::new(0);
+
 #[derive(Copy, Clone)]
 pub(super) struct AcpiHandler;
 
@@ -98,52 +110,66 @@ impl acpi::Handler for AcpiHandler {
         unsafe { u32::write_to_port(port, value) }
     }
 
-    fn read_pci_u8(&self, _address: PciAddress, _offset: u16) -> u8 {
-        todo!()
+    fn read_pci_u8(&self, address: PciAddress, offset: u16) -> u8 {
+        pcie::read_u8(address.into(), offset)
     }
 
-    fn read_pci_u16(&self, _address: PciAddress, _offset: u16) -> u16 {
-        todo!()
+    fn read_pci_u16(&self, address: PciAddress, offset: u16) -> u16 {
+        pcie::read_u16(address.into(), offset)
     }
 
-    fn read_pci_u32(&self, _address: PciAddress, _offset: u16) -> u32 {
-        todo!()
+    fn read_pci_u32(&self, address: PciAddress, offset: u16) -> u32 {
+        pcie::read_u32(address.into(), offset)
     }
 
-    fn write_pci_u8(&self, _address: PciAddress, _offset: u16, _value: u8) {
-        todo!()
+    fn write_pci_u8(&self, address: PciAddress, offset: u16, value: u8) {
+        pcie::write_u8(address.into(), offset, value);
     }
 
-    fn write_pci_u16(&self, _address: PciAddress, _offset: u16, _value: u16) {
-        todo!()
+    fn write_pci_u16(&self, address: PciAddress, offset: u16, value: u16) {
+        pcie::write_u16(address.into(), offset, value);
     }
 
-    fn write_pci_u32(&self, _address: PciAddress, _offset: u16, _value: u32) {
-        todo!()
+    fn write_pci_u32(&self, address: PciAddress, offset: u16, value: u32) {
+        pcie::write_u32(address.into(), offset, value);
     }
 
     fn nanos_since_boot(&self) -> u64 {
-        todo!()
+        time::monotonic_nanos()
     }
 
-    fn stall(&self, _microseconds: u64) {
-        todo!()
+    fn stall(&self, microseconds: u64) {
+        time::stall(microseconds);
     }
 
-    fn sleep(&self, _milliseconds: u64) {
-        todo!()
+    fn sleep(&self, milliseconds: u64) {
+        time::sleep(milliseconds);
     }
 
     fn create_mutex(&self) -> Handle {
-        todo!()
-    }
-
-    fn acquire(&self, _mutex: Handle, _timeout: u16) -> Result<(), AmlError> {
-        todo!()
+        let handle = NEXT_AML_MUTEX.fetch_add(1, Ordering::Relaxed);
+        assert!((handle as usize) < MAX_AML_MUTEXES, "Out of AML mutexes");
+        Handle(handle)
+    }
+
+    fn acquire(&self, mutex: Handle, timeout: u16) -> Result<(), AmlError> {
+        // the timeout is in milliseconds, 0xFFFF waits forever
+        let deadline = time::monotonic_nanos() + timeout as u64 * 1_000_000;
+        let lock = &AML_MUTEXES[mutex.0 as usize];
+        while lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if timeout != 0xFFFF && time::monotonic_nanos() >= deadline {
+                return Err(AmlError::MutexAcquireTimeout);
+            }
+            spin_loop();
+        }
+        Ok(())
     }
 
-    fn release(&self, _mutex: Handle) {
-        todo!()
+    fn release(&self, mutex: Handle) {
+        AML_MUTEXES[mutex.0 as usize].store(false, Ordering::Release);
     }
 }
 
@@ -178,3 +204,13 @@ pub fn load_acpi(rsdp_addr: u64) {
 pub(super) unsafe fn acpi_platform() -> &'static AcpiPlatform<AcpiHandler> {
     unsafe { PLATFORM.as_ref().expect("ACPI not initialized") }
 }
+
+/// The interrupt model described by the MADT, [`load_acpi`] has to be called first.
+pub fn interrupt_model() -> &'static InterruptModel {
+    unsafe { &acpi_platform().interrupt_model }
+}
+
+/// The processors in the MADT, if it lists any.
+pub fn processor_info() -> Option<&'static ProcessorInfo> {
+    unsafe { acpi_platform().processor_info.as_ref() }
+}
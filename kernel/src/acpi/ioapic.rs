@@ -0,0 +1,227 @@
+use core::intrinsics::{volatile_load, volatile_store};
+
+use acpi::platform::InterruptModel;
+use acpi::sdt::madt;
+use arrayvec::ArrayVec;
+use log::{debug, info, warn};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::acpi::init::interrupt_model;
+use crate::logger::LoggedAddress;
+use crate::memory;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const ENTRY_POLARITY_LOW: u32 = 1 << 13;
+const ENTRY_TRIGGER_LEVEL: u32 = 1 << 15;
+const ENTRY_MASKED: u32 = 1 << 16;
+
+const MAX_IO_APICS: usize = 8;
+const MAX_OVERRIDES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicError {
+    /// No I/O APIC has an input for this global system interrupt.
+    UnknownGsi(u32),
+    /// The destination doesn't fit into the 8 bit physical destination field.
+    ApicIdTooLarge(u32),
+}
+
+struct IoApic {
+    id: u8,
+    base: u64,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.inputs).contains(&gsi)
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            volatile_store((self.base + IOREGSEL) as *mut u32, reg);
+            volatile_load((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            volatile_store((self.base + IOREGSEL) as *mut u32, reg);
+            volatile_store((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+}
+
+/// Where an ISA IRQ ends up, as described by an Interrupt Source Override in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+static mut IO_APICS: ArrayVec<IoApic, MAX_IO_APICS> = ArrayVec::new_const();
+static mut OVERRIDES: ArrayVec<(u8, IsaRoute), MAX_OVERRIDES> = ArrayVec::new_const();
+// IOREGSEL and IOWIN have to be used as a pair
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Maps every I/O APIC in the MADT and masks all of their inputs.
+pub fn init() {
+    let InterruptModel::Apic(apic) = interrupt_model() else {
+        warn!("No I/O APIC without an APIC interrupt model");
+        return;
+    };
+
+    unsafe {
+        for io_apic in apic.io_apics.iter() {
+            let base = io_apic.address as u64;
+            memory::map_mmio(base..base + 0x20);
+
+            let mut io_apic = IoApic {
+                id: io_apic.id,
+                base,
+                gsi_base: io_apic.global_system_interrupt_base,
+                inputs: 0,
+            };
+            io_apic.inputs = ((io_apic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+            for input in 0..io_apic.inputs {
+                io_apic.write(REG_REDIRECTION_TABLE + input * 2, ENTRY_MASKED);
+            }
+
+            info!(
+                "I/O APIC {} at {:?}: GSIs {}..{}",
+                io_apic.id,
+                LoggedAddress::Physical(base),
+                io_apic.gsi_base,
+                io_apic.gsi_base + io_apic.inputs
+            );
+            debug!(
+                "I/O APIC {} ID register: {:#x}",
+                io_apic.id,
+                io_apic.read(REG_ID)
+            );
+            if IO_APICS.try_push(io_apic).is_err() {
+                warn!("Ignoring I/O APICs beyond the first {}", MAX_IO_APICS);
+                break;
+            }
+        }
+
+        for iso in apic.interrupt_source_overrides.iter() {
+            // ISA lines are active high and edge triggered unless the override says otherwise
+            let route = IsaRoute {
+                gsi: iso.global_system_interrupt,
+                polarity: match iso.polarity {
+                    madt::Polarity::ActiveLow => Polarity::ActiveLow,
+                    _ => Polarity::ActiveHigh,
+                },
+                trigger_mode: match iso.trigger_mode {
+                    madt::TriggerMode::Level => TriggerMode::Level,
+                    _ => TriggerMode::Edge,
+                },
+            };
+            debug!("ISA IRQ {} overridden: {:?}", iso.isa_source, route);
+            if OVERRIDES.try_push((iso.isa_source, route)).is_err() {
+                warn!(
+                    "Ignoring interrupt source overrides beyond the first {}",
+                    MAX_OVERRIDES
+                );
+                break;
+            }
+        }
+    }
+}
+
+/// Where the ISA `irq` is connected, which is the GSI with the same number unless overridden.
+pub fn isa_route(irq: u8) -> IsaRoute {
+    unsafe { OVERRIDES.iter() }
+        .find(|(source, _)| *source == irq)
+        .map(|(_, route)| *route)
+        .unwrap_or(IsaRoute {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        })
+}
+
+fn with_input<T>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> T) -> Result<T, IoApicError> {
+    let io_apic = unsafe { IO_APICS.iter() }
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(IoApicError::UnknownGsi(gsi))?;
+    let reg = REG_REDIRECTION_TABLE + (gsi - io_apic.gsi_base) * 2;
+
+    Ok(without_interrupts(|| {
+        let _guard = LOCK.lock();
+        f(io_apic, reg)
+    }))
+}
+
+/// Delivers `gsi` as `vector` to the Local APIC with ID `apic_id` and unmasks it.
+pub fn route(
+    gsi: u32,
+    vector: u8,
+    apic_id: u32,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+) -> Result<(), IoApicError> {
+    if apic_id > 0xFF {
+        return Err(IoApicError::ApicIdTooLarge(apic_id));
+    }
+    let mut low = vector as u32; // fixed delivery, physical destination
+    if polarity == Polarity::ActiveLow {
+        low |= ENTRY_POLARITY_LOW;
+    }
+    if trigger_mode == TriggerMode::Level {
+        low |= ENTRY_TRIGGER_LEVEL;
+    }
+
+    with_input(gsi, |io_apic, reg| unsafe {
+        // masked while the destination is changed, so nothing is delivered half programmed
+        io_apic.write(reg, ENTRY_MASKED);
+        io_apic.write(reg + 1, apic_id << 24);
+        io_apic.write(reg, low);
+    })?;
+
+    debug!(
+        "Routed GSI {} to vector {:#04x} on APIC {} ({:?}, {:?})",
+        gsi, vector, apic_id, polarity, trigger_mode
+    );
+    Ok(())
+}
+
+/// Routes the ISA `irq`, taking Interrupt Source Overrides into account.
+pub fn route_isa(irq: u8, vector: u8, apic_id: u32) -> Result<(), IoApicError> {
+    let isa = isa_route(irq);
+    route(isa.gsi, vector, apic_id, isa.polarity, isa.trigger_mode)
+}
+
+pub fn mask(gsi: u32) -> Result<(), IoApicError> {
+    with_input(gsi, |io_apic, reg| unsafe {
+        io_apic.write(reg, io_apic.read(reg) | ENTRY_MASKED);
+    })
+}
+
+pub fn unmask(gsi: u32) -> Result<(), IoApicError> {
+    with_input(gsi, |io_apic, reg| unsafe {
+        io_apic.write(reg, io_apic.read(reg) & !ENTRY_MASKED);
+    })
+}
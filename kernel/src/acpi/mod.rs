@@ -1,12 +1,17 @@
 use bootloader_api::BootInfo;
 
+pub mod aml;
+pub mod apic;
 pub mod hpet;
 pub mod init;
-pub mod apic;
+pub mod ioapic;
 pub(crate) mod pcie;
 
 pub fn init(boot_info: &'static BootInfo) {
-    init::load_acpi(boot_info.rsdp_addr.into_option().expect("RSDP Address not passed by bootloader"));
+    init::load_acpi(
+        boot_info
+            .rsdp_addr
+            .into_option()
+            .expect("RSDP Address not passed by bootloader"),
+    );
 }
-
-
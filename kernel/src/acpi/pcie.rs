@@ -1,36 +1,226 @@
+use core::hint::spin_loop;
+use core::intrinsics::{volatile_load, volatile_store};
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use crate::acpi::init::acpi_platform;
+use crate::cmdline;
 use crate::logger::LoggedAddress;
 use crate::memory;
-use crate::memory::ERROR_ADDRESS;
+use crate::memory::tlb;
+use crate::pci::PciAddress;
 use acpi::sdt::mcfg::Mcfg;
-use log::{debug, info};
+use arrayvec::ArrayVec;
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+// every bus gets 32 devices * 8 functions * 4 KiB of configuration space
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+const CONFIG_SPACE_SIZE: u16 = 4096;
+const LEGACY_CONFIG_SPACE_SIZE: u16 = 256;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+const MAX_ECAM_WINDOWS: usize = 16;
+
+/// One allocation in the MCFG: the ECAM region of some buses in a segment group.
+struct EcamWindow {
+    segment: u16,
+    bus_start: u8,
+    bus_end: u8,
+    /// The address of bus 0's configuration space, even if the window starts at a later bus.
+    base: u64,
+    /// One bit per bus whose 1 MiB is mapped.
+    mapped: [AtomicU64; 4],
+    map_lock: Mutex<()>,
+}
 
-static mut PCIE_BASE_ADDR: u64 = ERROR_ADDRESS;
+impl EcamWindow {
+    fn handles(&self, address: PciAddress) -> bool {
+        self.segment == address.segment && (self.bus_start..=self.bus_end).contains(&address.bus)
+    }
+
+    fn bus_base(&self, bus: u8) -> u64 {
+        self.base + bus as u64 * ECAM_BUS_SIZE
+    }
 
+    fn map_bus(&self, bus: u8) {
+        let (word, bit) = (bus as usize / 64, 1 << (bus % 64));
+        if self.mapped[word].load(Ordering::Acquire) & bit != 0 {
+            return;
+        }
+
+        // Interrupts are off so a handler accessing configuration space can't spin on the lock
+        // held by the code it interrupted. Mapping uncached memory shoots down the TLB of every
+        // CPU and the one holding the lock may be waiting for this one, so shootdowns are served
+        // while waiting.
+        without_interrupts(|| {
+            let _guard = loop {
+                if let Some(guard) = self.map_lock.try_lock() {
+                    break guard;
+                }
+                tlb::serve();
+                spin_loop();
+            };
+            // another CPU may have mapped the bus while this one waited for the lock
+            if self.mapped[word].load(Ordering::Acquire) & bit == 0 {
+                let base = self.bus_base(bus);
+                memory::map_mmio(base..base + ECAM_BUS_SIZE);
+                self.mapped[word].fetch_or(bit, Ordering::Release);
+            }
+        });
+    }
+}
+
+static mut ECAM_WINDOWS: ArrayVec<EcamWindow, MAX_ECAM_WINDOWS> = ArrayVec::new_const();
+// CONFIG_ADDRESS and CONFIG_DATA have to be used as a pair
+static LEGACY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Finds the ECAM regions in the MCFG. Without one, configuration space is accessed through
+/// the legacy I/O ports instead.
+///
+/// A bus is mapped the first time it is accessed, since most of the 256 MiB a segment group can
+/// take is usually empty. `pcie=full` maps every bus up front instead.
 pub fn init() {
     unsafe {
-        let (mcfg_addr, head) = acpi_platform()
+        let Some((mcfg_addr, _head)) = acpi_platform()
             .tables
             .table_headers()
             .find(|(_physical_address, header)| header.signature == acpi::sdt::Signature::MCFG)
-            .expect("PCIe is not supported");
+        else {
+            warn!("No MCFG, using legacy PCI configuration access");
+            return;
+        };
 
         let mcfg = &*(mcfg_addr as *const Mcfg);
-        let entries = mcfg.entries();
-        if entries.len() != 1 {
-            unimplemented!(
-                "Non-contiguous PCIe configuration space/multiple PCI segment groups are not yet supported"
+        for entry in mcfg.entries() {
+            let window = EcamWindow {
+                segment: entry.pci_segment_group,
+                bus_start: entry.bus_number_start,
+                bus_end: entry.bus_number_end,
+                base: entry.base_address,
+                mapped: [const { AtomicU64::new(0) }; 4],
+                map_lock: Mutex::new(()),
+            };
+            info!(
+                "PCIe ECAM for segment {:04x} buses {:02x}..={:02x} at {:?}",
+                window.segment,
+                window.bus_start,
+                window.bus_end,
+                LoggedAddress::Physical(window.bus_base(window.bus_start))
             );
+            if cmdline::get("pcie") == Some("full") {
+                for bus in window.bus_start..=window.bus_end {
+                    window.map_bus(bus);
+                }
+            }
+            if ECAM_WINDOWS.try_push(window).is_err() {
+                warn!(
+                    "Ignoring ECAM windows beyond the first {}",
+                    MAX_ECAM_WINDOWS
+                );
+                break;
+            }
         }
+    }
+}
 
-        let entry = entries.first().unwrap_unchecked();
-        PCIE_BASE_ADDR = entry.base_address;
-        debug!(
-            "Found PCIe configuration space at {:?}",
-            LoggedAddress::Physical(PCIE_BASE_ADDR)
-        );
-        memory::map_identity(
-            PCIE_BASE_ADDR..(PCIE_BASE_ADDR + (entry.bus_number_end as u64 - entry.bus_number_start as u64 + 1) * 4096),
+/// The segment groups with ECAM windows and the first bus of each window, which is where
+/// scanning a segment starts.
+pub fn root_buses() -> impl Iterator<Item = (u16, u8)> {
+    unsafe { ECAM_WINDOWS.iter() }.map(|window| (window.segment, window.bus_start))
+}
+
+/// Where `offset` in the configuration space of `address` is in its ECAM window, if it has one.
+fn ecam_address(address: PciAddress, offset: u16) -> Option<u64> {
+    if offset >= CONFIG_SPACE_SIZE {
+        return None;
+    }
+    let window = unsafe { ECAM_WINDOWS.iter() }.find(|window| window.handles(address))?;
+    window.map_bus(address.bus);
+
+    Some(
+        window.bus_base(address.bus)
+            + ((address.device as u64) << 15)
+            + ((address.function as u64) << 12)
+            + offset as u64,
+    )
+}
+
+/// Selects the dword containing `offset` through CONFIG_ADDRESS and runs `f` with the port the
+/// value is at. The legacy mechanism only reaches segment 0 and the first 256 bytes.
+fn with_legacy_port<T>(address: PciAddress, offset: u16, f: impl FnOnce(u16) -> T) -> Option<T> {
+    if address.segment != 0 || offset >= LEGACY_CONFIG_SPACE_SIZE {
+        return None;
+    }
+
+    let config_address = CONFIG_ADDRESS_ENABLE
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset & 0xFC) as u32;
+    Some(without_interrupts(|| {
+        let _guard = LEGACY_LOCK.lock();
+        unsafe { u32::write_to_port(CONFIG_ADDRESS, config_address) };
+        f(CONFIG_DATA + (offset & 0b11))
+    }))
+}
+
+/// Reads from configuration space through ECAM, or the legacy ports without an ECAM window.
+///
+/// `offset` has to be aligned to the size of `T`.
+fn read<T: PortRead>(address: PciAddress, offset: u16) -> Option<T> {
+    match ecam_address(address, offset) {
+        Some(ecam) => Some(unsafe { volatile_load(ecam as *const T) }),
+        None => with_legacy_port(address, offset, |port| unsafe { T::read_from_port(port) }),
+    }
+}
+
+fn write<T: PortWrite>(address: PciAddress, offset: u16, value: T) {
+    let written = match ecam_address(address, offset) {
+        Some(ecam) => {
+            unsafe { volatile_store(ecam as *mut T, value) };
+            true
+        }
+        None => with_legacy_port(address, offset, |port| unsafe {
+            T::write_to_port(port, value)
+        })
+        .is_some(),
+    };
+    if !written {
+        warn!(
+            "Dropping write to unreachable PCI configuration space {}+{:#x}",
+            address, offset
         );
     }
 }
+
+// Reads from functions that can't be reached return all ones, like reads from functions that
+// don't exist do.
+
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    read(address, offset).unwrap_or(u8::MAX)
+}
+
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    read(address, offset).unwrap_or(u16::MAX)
+}
+
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    read(address, offset).unwrap_or(u32::MAX)
+}
+
+pub fn write_u8(address: PciAddress, offset: u16, value: u8) {
+    write(address, offset, value);
+}
+
+pub fn write_u16(address: PciAddress, offset: u16, value: u16) {
+    write(address, offset, value);
+}
+
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    write(address, offset, value);
+}
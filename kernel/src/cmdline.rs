@@ -0,0 +1,71 @@
+use crate::initrd;
+
+/// The kernel command line, e.g. `log=debug,acpi=trace heap=16M hpet=off`.
+///
+/// It is a whitespace separated list of `key=value` options (or bare `key` flags) which build.rs
+/// puts into the initrd as `cmdline`, taken from the `KERNEL_CMDLINE` environment variable.
+static mut CMDLINE: &str = "";
+
+/// Reads the command line from the initrd, so [`initrd::init`] has to be called first.
+pub fn init() {
+    let Some(bytes) = initrd::open("cmdline") else {
+        return;
+    };
+
+    unsafe {
+        CMDLINE = core::str::from_utf8(bytes).unwrap_or("").trim();
+    }
+}
+
+pub fn raw() -> &'static str {
+    unsafe { CMDLINE }
+}
+
+/// Iterates over all options as `(key, value)`, `value` is `None` for bare flags.
+pub fn options() -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+    raw()
+        .split_whitespace()
+        .map(|option| match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        })
+}
+
+/// Returns the value of the last option named `key`.
+pub fn get(key: &str) -> Option<&'static str> {
+    options()
+        .filter(|(k, _)| *k == key)
+        .last()
+        .and_then(|(_, value)| value)
+}
+
+/// Whether a driver or feature named `key` should be enabled, i.e. it wasn't set to `off`, `0`, `no` or `false`.
+pub fn enabled(key: &str) -> bool {
+    !matches!(get(key), Some("off" | "0" | "no" | "false"))
+}
+
+/// Parses a size like `16M`, `512K`, `1G` or a plain number of bytes.
+pub fn parse_size(value: &str) -> Option<usize> {
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 10),
+        b'M' | b'm' => (&value[..value.len() - 1], 20),
+        b'G' | b'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_size;
+
+    #[test_case]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("16M"), Some(16 * 1024 * 1024));
+        assert_eq!(parse_size("512k"), Some(512 * 1024));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("twelve"), None);
+    }
+}
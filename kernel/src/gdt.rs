@@ -1,57 +1,56 @@
 #![allow(unused_unsafe)]
 
-use lazy_static::lazy_static;
+use alloc::boxed::Box;
 use log::info;
-use x86_64::VirtAddr;
+use x86_64::PrivilegeLevel;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 
-pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = unsafe {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
-        tss
-    };
-}
+use crate::memory::stack;
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
-}
-
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const IST_STACK_SIZE: u64 = 4096 * 5;
+
+/// The TSS of a single CPU.
+///
+/// Double faults, NMIs and machine checks can arrive while the current stack is unusable (e.g.
+/// after it overflowed into its guard page), so they get known good stacks of their own.
+fn new_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        stack::allocate("double fault", IST_STACK_SIZE);
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = stack::allocate("NMI", IST_STACK_SIZE);
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] =
+        stack::allocate("machine check", IST_STACK_SIZE);
+    tss
 }
 
+/// Loads a new GDT and TSS on the current CPU, every CPU needs its own TSS.
+///
+/// The IST stacks are mapped and the tables allocated here, so [`crate::memory::init`] and the
+/// heap have to be initialized first, as well as the CPU's [`crate::percpu`] data.
 pub fn init() {
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(new_tss()));
+    percpu!(tss).set(Some(tss));
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_selector = gdt.append(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
+
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(code_selector);
+        // whatever the bootloader or the AP trampoline left here doesn't exist in this GDT
+        let null = SegmentSelector::new(0, PrivilegeLevel::Ring0);
+        SS::set_reg(null);
+        DS::set_reg(null);
+        ES::set_reg(null);
+        load_tss(tss_selector);
     }
     info!("Loaded GDT");
 }
@@ -0,0 +1,176 @@
+use bootloader_api::BootInfo;
+
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_LEN: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIR: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+
+static mut ARCHIVE: &[u8] = &[];
+
+/// A file or directory in the initrd.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    name: &'static str,
+    mode: u32,
+    data: &'static [u8],
+}
+
+impl Entry {
+    /// The path of this entry relative to the root of the initrd, without a leading `/`.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_FILE
+    }
+}
+
+/// Picks up the initrd the bootloader loaded for us.
+///
+/// The bootloader already maps the ramdisk into the kernel's address space, so this only has to
+/// check that it is a cpio (newc) archive as written by build.rs. Anything else is ignored.
+///
+/// This runs before the logger is configured, so it doesn't log anything itself.
+pub fn init(boot_info: &'static BootInfo) {
+    let Some(addr) = boot_info.ramdisk_addr.into_option() else {
+        return;
+    };
+
+    let archive =
+        unsafe { core::slice::from_raw_parts(addr as *const u8, boot_info.ramdisk_len as usize) };
+    if archive.starts_with(CPIO_MAGIC) {
+        unsafe {
+            ARCHIVE = archive;
+        }
+    }
+}
+
+/// Iterates over every entry in the initrd.
+pub fn entries() -> Entries {
+    Entries {
+        rest: unsafe { ARCHIVE },
+    }
+}
+
+pub fn find(path: &str) -> Option<Entry> {
+    let path = path.trim_matches('/');
+    entries().find(|entry| entry.name == path)
+}
+
+/// Returns the contents of the file at `path`.
+pub fn open(path: &str) -> Option<&'static [u8]> {
+    find(path).filter(Entry::is_file).map(|entry| entry.data)
+}
+
+/// Iterates over the direct children of the directory at `path` (`""` or `"/"` for the root).
+pub fn read_dir(path: &str) -> impl Iterator<Item = Entry> {
+    let path = path.trim_matches('/');
+    entries().filter(move |entry| {
+        let parent = entry.name.rsplit_once('/').map_or("", |(parent, _)| parent);
+        parent == path
+    })
+}
+
+pub struct Entries {
+    rest: &'static [u8],
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let (entry, len) = parse_entry(self.rest)?;
+        self.rest = &self.rest[len..];
+        if entry.name == CPIO_TRAILER {
+            self.rest = &[];
+            return None;
+        }
+        Some(entry)
+    }
+}
+
+/// Parses one entry at the start of `archive`, returning it and its length including padding.
+fn parse_entry(archive: &'static [u8]) -> Option<(Entry, usize)> {
+    let header = archive.get(..CPIO_HEADER_LEN)?;
+    if !header.starts_with(CPIO_MAGIC) {
+        return None;
+    }
+
+    // 13 fields of 8 hex digits each follow the magic
+    let field = |i: usize| parse_hex(&header[6 + i * 8..6 + (i + 1) * 8]);
+    let mode = field(1)?;
+    let file_size = field(6)? as usize;
+    let name_size = field(11)? as usize;
+
+    let name_end = CPIO_HEADER_LEN + name_size;
+    // the name includes a terminating NUL
+    let name = archive.get(CPIO_HEADER_LEN..name_end - 1)?;
+    let name = core::str::from_utf8(name).ok()?;
+
+    let data_start = align4(name_end);
+    let data = archive.get(data_start..data_start + file_size)?;
+    let len = align4(data_start + file_size).min(archive.len());
+
+    let name = name.trim_start_matches("./");
+    Some((Entry { name, mode, data }, len))
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    u32::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entries, Entry};
+
+    // `cmdline` and `etc/motd` as written by build.rs
+    static ARCHIVE: &[u8] = b"\
+07070100000001000081a40000000000000000000000010000000000000004\
+000000000000000000000000000000000000000800000000cmdline\0\0\0log=\
+07070100000002000041ed0000000000000000000000010000000000000000\
+000000000000000000000000000000000000000400000000etc\0\0\0\
+07070100000003000081a40000000000000000000000010000000000000003\
+000000000000000000000000000000000000000900000000etc/motd\0\0hi\n\0\
+07070100000004000000000000000000000000000000010000000000000000\
+000000000000000000000000000000000000000b00000000TRAILER!!!\0\0\0\0";
+
+    fn entries() -> impl Iterator<Item = Entry> {
+        Entries { rest: ARCHIVE }
+    }
+
+    #[test_case]
+    fn parses_newc_archive() {
+        let mut entries = entries();
+
+        let cmdline = entries.next().unwrap();
+        assert_eq!(cmdline.name(), "cmdline");
+        assert!(cmdline.is_file());
+        assert_eq!(cmdline.data(), b"log=");
+
+        let etc = entries.next().unwrap();
+        assert_eq!(etc.name(), "etc");
+        assert!(etc.is_dir());
+
+        let motd = entries.next().unwrap();
+        assert_eq!(motd.name(), "etc/motd");
+        assert_eq!(motd.data(), b"hi\n");
+
+        assert!(entries.next().is_none());
+    }
+}
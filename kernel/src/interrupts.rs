@@ -1,12 +1,14 @@
+pub mod controller;
+pub mod exceptions;
+pub mod irq;
 pub mod pit;
 
 use lazy_static::lazy_static;
-use log::{error, info};
+use log::info;
 use pic8259::ChainedPics;
 use x86_64::instructions::hlt;
 use x86_64::instructions::port::PortWrite;
-use x86_64::registers::control::Cr2;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::InterruptDescriptorTable;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -23,20 +25,8 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        // unsafe {
-        //     idt.double_fault
-        //         .set_handler_fn(double_fault_handler)
-        //         .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        // }
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.general_protection_fault.set_handler_fn(gpf_handler);
-
-        idt[32].set_handler_fn(timer_interrupt_handler);
-        for i in 32..48 {
-            idt[i].set_handler_fn(nothing_isr);
-        }
+        exceptions::install(&mut idt);
+        irq::install_stubs(&mut idt);
 
         idt
     };
@@ -68,31 +58,6 @@ pub fn init_apic() {
     // acpi::
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    error!("BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
-) {
-    panic!("PAGE FAULT AT 0x{:016X}\n{:#?}", Cr2::read_raw(), stack_frame);
-}
-
-extern "x86-interrupt" fn gpf_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
-    panic!("GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
 impl InterruptIndex {
     pub fn as_u8(self) -> u8 {
         self as u8
@@ -102,19 +67,3 @@ impl InterruptIndex {
         usize::from(self.as_u8())
     }
 }
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        print!(".");
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-}
-
-extern "x86-interrupt" fn nothing_isr(_stack_frame: InterruptStackFrame) {
-    // print!("Int!");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-}
\ No newline at end of file
@@ -0,0 +1,56 @@
+use ::acpi::platform::InterruptModel;
+use log::info;
+
+use crate::acpi;
+use crate::interrupts::PICS;
+
+/// The controller interrupts are delivered through and acknowledged at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    /// The legacy 8259 pair, remapped to vectors 32..48.
+    Pic,
+    /// The Local APIC, with external interrupts coming in through the I/O APICs.
+    Apic,
+}
+
+static mut CONTROLLER: InterruptController = InterruptController::Pic;
+
+/// Picks the controller based on the interrupt model in the MADT and brings it up.
+///
+/// The PICs are remapped either way, so a spurious IRQ from them can't be mistaken for an
+/// exception. In APIC mode they are masked afterward.
+pub fn init() {
+    unsafe {
+        PICS.lock().initialize();
+    }
+
+    let controller = match acpi::init::interrupt_model() {
+        InterruptModel::Apic(_) => InterruptController::Apic,
+        _ => InterruptController::Pic,
+    };
+
+    if controller == InterruptController::Apic {
+        super::disable_8259_pic();
+        acpi::apic::init();
+        acpi::ioapic::init();
+    }
+
+    unsafe {
+        CONTROLLER = controller;
+    }
+    info!("Using {:?} interrupt controller", controller);
+}
+
+pub fn current() -> InterruptController {
+    unsafe { CONTROLLER }
+}
+
+/// Signals the end of the interrupt on `vector` to whichever controller delivered it.
+pub fn end_of_interrupt(vector: u8) {
+    match current() {
+        InterruptController::Pic => unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        },
+        InterruptController::Apic => acpi::apic::write_eoi(),
+    }
+}
@@ -0,0 +1,341 @@
+use core::arch::naked_asm;
+use core::fmt::{self, Display, Formatter};
+use core::sync::atomic::Ordering;
+
+use log::error;
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode, SelectorErrorCode};
+
+use crate::gdt;
+use crate::memory::stack;
+use crate::percpu;
+
+const NMI_VECTOR: u64 = 2;
+const BREAKPOINT_VECTOR: u64 = 3;
+const DOUBLE_FAULT_VECTOR: u64 = 8;
+const PAGE_FAULT_VECTOR: u64 = 14;
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "DIVIDE ERROR",
+    "DEBUG",
+    "NON-MASKABLE INTERRUPT",
+    "BREAKPOINT",
+    "OVERFLOW",
+    "BOUND RANGE EXCEEDED",
+    "INVALID OPCODE",
+    "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS",
+    "SEGMENT NOT PRESENT",
+    "STACK-SEGMENT FAULT",
+    "GENERAL PROTECTION FAULT",
+    "PAGE FAULT",
+    "RESERVED",
+    "X87 FLOATING-POINT EXCEPTION",
+    "ALIGNMENT CHECK",
+    "MACHINE CHECK",
+    "SIMD FLOATING-POINT EXCEPTION",
+    "VIRTUALIZATION EXCEPTION",
+    "CONTROL PROTECTION EXCEPTION",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "HYPERVISOR INJECTION EXCEPTION",
+    "VMM COMMUNICATION EXCEPTION",
+    "SECURITY EXCEPTION",
+    "RESERVED",
+];
+
+/// Everything the exception entry stubs save on the stack, lowest address first.
+///
+/// The general purpose registers are pushed by [`exception_common`], the vector and error code
+/// (0 for exceptions without one) by the per-vector stub and the rest by the CPU.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    pub fn name(&self) -> &'static str {
+        EXCEPTION_NAMES
+            .get(self.vector as usize)
+            .copied()
+            .unwrap_or("UNKNOWN")
+    }
+
+    fn fmt_error_code(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let code = self.error_code;
+        match self.vector {
+            // invalid TSS, segment not present, stack-segment fault and GPF report a selector
+            10..=13 if code != 0 => {
+                writeln!(
+                    f,
+                    "Error code: {:#x} {:?}",
+                    code,
+                    SelectorErrorCode::new_truncate(code)
+                )
+            }
+            PAGE_FAULT_VECTOR => {
+                writeln!(
+                    f,
+                    "Error code: {:#x} {:?}",
+                    code,
+                    PageFaultErrorCode::from_bits_truncate(code)
+                )?;
+                writeln!(f, "Accessed address: {:#018x}", Cr2::read_raw())
+            }
+            21 => {
+                let kind = match code & 0x7fff {
+                    1 => "near return",
+                    2 => "far return",
+                    3 => "missing ENDBRANCH",
+                    4 => "RSTORSSP",
+                    5 => "SETSSBSY",
+                    _ => "unknown",
+                };
+                writeln!(f, "Error code: {code:#x} ({kind})")
+            }
+            8 | 10..=13 | 17 | 29 | 30 => writeln!(f, "Error code: {code:#x}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Display for ExceptionFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "EXCEPTION: {} (vector {})", self.name(), self.vector)?;
+        self.fmt_error_code(f)?;
+        writeln!(
+            f,
+            "RIP: {:#018x}  CS: {:#06x}  RFLAGS: {:#018x}",
+            self.rip, self.cs, self.rflags
+        )?;
+        writeln!(f, "RSP: {:#018x}  SS: {:#06x}", self.rsp, self.ss)?;
+        writeln!(
+            f,
+            "RAX: {:#018x}  RBX: {:#018x}  RCX: {:#018x}",
+            self.rax, self.rbx, self.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX: {:#018x}  RSI: {:#018x}  RDI: {:#018x}",
+            self.rdx, self.rsi, self.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP: {:#018x}  R8:  {:#018x}  R9:  {:#018x}",
+            self.rbp, self.r8, self.r9
+        )?;
+        writeln!(
+            f,
+            "R10: {:#018x}  R11: {:#018x}  R12: {:#018x}",
+            self.r10, self.r11, self.r12
+        )?;
+        writeln!(
+            f,
+            "R13: {:#018x}  R14: {:#018x}  R15: {:#018x}",
+            self.r13, self.r14, self.r15
+        )?;
+        write!(
+            f,
+            "CR0: {:#018x}  CR2: {:#018x}  CR3: {:#018x}  CR4: {:#018x}",
+            Cr0::read_raw(),
+            Cr2::read_raw(),
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )
+    }
+}
+
+extern "C" fn handle_exception(frame: &mut ExceptionFrame) {
+    if frame.vector == BREAKPOINT_VECTOR {
+        error!("{}", frame);
+        return;
+    }
+
+    // NMIs sent as IPIs are expected, any other one means a hardware error
+    if frame.vector == NMI_VECTOR && percpu::current().nmi_pending.swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    // Running into a guard page can't push the page fault's frame onto the same stack, so it
+    // usually ends up as a double fault on its IST stack with CR2 still pointing at the guard page.
+    if matches!(frame.vector, DOUBLE_FAULT_VECTOR | PAGE_FAULT_VECTOR)
+        && let Some(stack) = stack::guard_page_owner(Cr2::read_raw())
+    {
+        panic!(
+            "STACK OVERFLOW: {} stack overflowed at RSP {:#018x}\n{}",
+            stack, frame.rsp, frame
+        );
+    }
+
+    panic!("{}", frame);
+}
+
+/// Saves the general purpose registers below the vector and error code pushed by the stubs,
+/// calls [`handle_exception`] and returns from the interrupt if it does.
+#[unsafe(naked)]
+extern "C" fn exception_common() {
+    naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // the CPU aligned the stack to 16 bytes and 22 qwords were pushed since, so it still is
+        "mov rdi, rsp",
+        "cld",
+        "call {handler}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // vector and error code
+        "add rsp, 16",
+        "iretq",
+        handler = sym handle_exception,
+    );
+}
+
+macro_rules! exception_stub {
+    ($name:ident, $vector:literal) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            );
+        }
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            );
+        }
+    };
+}
+
+exception_stub!(divide_error, 0);
+exception_stub!(debug, 1);
+exception_stub!(non_maskable_interrupt, 2);
+exception_stub!(breakpoint, 3);
+exception_stub!(overflow, 4);
+exception_stub!(bound_range_exceeded, 5);
+exception_stub!(invalid_opcode, 6);
+exception_stub!(device_not_available, 7);
+exception_stub!(double_fault, 8, error_code);
+exception_stub!(invalid_tss, 10, error_code);
+exception_stub!(segment_not_present, 11, error_code);
+exception_stub!(stack_segment_fault, 12, error_code);
+exception_stub!(general_protection_fault, 13, error_code);
+exception_stub!(page_fault, 14, error_code);
+exception_stub!(x87_floating_point, 16);
+exception_stub!(alignment_check, 17, error_code);
+exception_stub!(machine_check, 18);
+exception_stub!(simd_floating_point, 19);
+exception_stub!(virtualization, 20);
+exception_stub!(cp_protection_exception, 21, error_code);
+exception_stub!(hv_injection_exception, 28);
+exception_stub!(vmm_communication_exception, 29, error_code);
+exception_stub!(security_exception, 30, error_code);
+
+/// Installs a handler for every architectural exception.
+///
+/// Vector 9 (coprocessor segment overrun) isn't raised by any CPU since the 386 and the
+/// `x86_64` crate doesn't expose its entry.
+///
+/// Double faults, NMIs and machine checks run on their own IST stacks (see [`gdt`]).
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    macro_rules! set {
+        ($($entry:ident $(=> $ist:expr)?),* $(,)?) => {
+            $(
+                unsafe {
+                    let _options = idt.$entry.set_handler_addr(VirtAddr::from_ptr($entry as *const ()));
+                    $(_options.set_stack_index($ist);)?
+                }
+            )*
+        };
+    }
+
+    set!(
+        divide_error,
+        debug,
+        non_maskable_interrupt => gdt::NMI_IST_INDEX,
+        breakpoint,
+        overflow,
+        bound_range_exceeded,
+        invalid_opcode,
+        device_not_available,
+        double_fault => gdt::DOUBLE_FAULT_IST_INDEX,
+        invalid_tss,
+        segment_not_present,
+        stack_segment_fault,
+        general_protection_fault,
+        page_fault,
+        x87_floating_point,
+        alignment_check,
+        machine_check => gdt::MACHINE_CHECK_IST_INDEX,
+        simd_floating_point,
+        virtualization,
+        cp_protection_exception,
+        hv_injection_exception,
+        vmm_communication_exception,
+        security_exception,
+    );
+}
@@ -0,0 +1,473 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::arch::{global_asm, naked_asm};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arrayvec::ArrayVec;
+use log::{debug, warn};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts::{are_enabled, without_interrupts};
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+/// The first vector that isn't reserved for exceptions.
+pub const FIRST_VECTOR: u8 = 32;
+/// The Local APIC's spurious interrupt vector, which must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const VECTOR_COUNT: usize = 256 - FIRST_VECTOR as usize;
+const STUB_SIZE: usize = 16;
+/// How many handlers can share one vector.
+const MAX_SHARED: usize = 8;
+/// How many handlers freed from interrupt context can wait to be dropped.
+const MAX_RETIRED: usize = 32;
+
+// Vectors below this are left for the legacy ISA IRQs, which the PIC delivers at 32..48.
+const DYNAMIC_VECTORS_START: u8 = 48;
+
+/// What a handler tells the dispatcher about an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt came from this handler's device and was dealt with.
+    Handled,
+    /// Not this handler's device, which happens on shared lines.
+    NotHandled,
+}
+
+/// A plain handler, called with the context pointer given to [`request`].
+pub type IrqHandler = fn(context: *mut ()) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// Exceptions and the spurious vector can't be requested.
+    ReservedVector,
+    /// The vector is claimed by someone else or one side doesn't want to share it.
+    Busy,
+    /// Every vector is in use, or too many handlers share this one.
+    NoSpace,
+}
+
+/// Interrupt counters of a single vector.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IrqStats {
+    pub count: u64,
+    /// Interrupts no handler claimed, including spurious ones.
+    pub unhandled: u64,
+}
+
+/// Identifies an installed handler, which is removed again when the handle is dropped.
+#[derive(Debug)]
+#[must_use = "the handler is removed as soon as the handle is dropped"]
+pub struct IrqHandle {
+    vector: u8,
+    id: u64,
+}
+
+impl IrqHandle {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// Keeps the handler installed for good.
+    pub fn leak(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for IrqHandle {
+    fn drop(&mut self) {
+        remove(self.vector, self.id);
+    }
+}
+
+enum Callback {
+    Fn(IrqHandler, *mut ()),
+    // a closure can run on two CPUs at once if its vector fires on both
+    Closure(Mutex<Box<dyn FnMut() -> IrqReturn + Send>>),
+}
+
+impl Callback {
+    fn call(&self) -> IrqReturn {
+        match self {
+            Callback::Fn(handler, context) => handler(*context),
+            Callback::Closure(closure) => closure.lock()(),
+        }
+    }
+}
+
+struct Action {
+    id: u64,
+    name: &'static str,
+    callback: Callback,
+}
+
+// The context pointer belongs to the driver which requested the interrupt, which has to make
+// sure it can be used from interrupt context on any CPU.
+unsafe impl Send for Action {}
+unsafe impl Sync for Action {}
+
+struct Vector {
+    /// Set by [`allocate_vector`] until [`release_vector`], so nobody else picks the vector while
+    /// its owner has no handlers installed.
+    claimed: bool,
+    shared: bool,
+    /// Shared with [`dispatch`], which runs them without holding the lock.
+    actions: ArrayVec<Arc<Action>, MAX_SHARED>,
+}
+
+impl Vector {
+    const fn new() -> Self {
+        Self {
+            claimed: false,
+            shared: false,
+            actions: ArrayVec::new_const(),
+        }
+    }
+
+    fn is_free(&self) -> bool {
+        !self.claimed && self.actions.is_empty()
+    }
+}
+
+static VECTORS: [Mutex<Vector>; VECTOR_COUNT] = [const { Mutex::new(Vector::new()) }; VECTOR_COUNT];
+static COUNTS: [AtomicU64; VECTOR_COUNT] = [const { AtomicU64::new(0) }; VECTOR_COUNT];
+static UNHANDLED: [AtomicU64; VECTOR_COUNT] = [const { AtomicU64::new(0) }; VECTOR_COUNT];
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// Handlers freed with interrupts disabled, which are dropped by the next [`free`] that isn't.
+static RETIRED: Mutex<ArrayVec<Arc<Action>, MAX_RETIRED>> = Mutex::new(ArrayVec::new_const());
+
+fn index(vector: u8) -> Result<usize, IrqError> {
+    if vector < FIRST_VECTOR || vector == SPURIOUS_VECTOR {
+        return Err(IrqError::ReservedVector);
+    }
+    Ok((vector - FIRST_VECTOR) as usize)
+}
+
+/// Claims an unused vector for a device which doesn't have a fixed one (e.g. MSI or a GSI routed
+/// through the I/O APIC). It stays claimed until it is given back with [`release_vector`], even
+/// when no handlers are installed on it.
+pub fn allocate_vector() -> Option<u8> {
+    without_interrupts(|| {
+        (DYNAMIC_VECTORS_START..SPURIOUS_VECTOR).find(|&vector| {
+            let mut entry = VECTORS[(vector - FIRST_VECTOR) as usize].lock();
+            if entry.is_free() {
+                entry.claimed = true;
+                true
+            } else {
+                false
+            }
+        })
+    })
+}
+
+/// Gives back a vector claimed by [`allocate_vector`]. Handlers still installed on it stay until
+/// they are freed.
+pub fn release_vector(vector: u8) {
+    let Ok(index) = index(vector) else {
+        return;
+    };
+    without_interrupts(|| VECTORS[index].lock().claimed = false);
+}
+
+/// Installs `handler` on `vector`, it is called with `context` for every interrupt on it.
+///
+/// Handlers on a `shared` vector are called in the order they were installed until one of them
+/// returns [`IrqReturn::Handled`]. A vector can only be shared if every handler on it agrees.
+pub fn request(
+    vector: u8,
+    name: &'static str,
+    shared: bool,
+    handler: IrqHandler,
+    context: *mut (),
+) -> Result<IrqHandle, IrqError> {
+    install(vector, name, shared, Callback::Fn(handler, context))
+}
+
+/// Like [`request`], but with a closure owning whatever state the handler needs.
+pub fn request_closure(
+    vector: u8,
+    name: &'static str,
+    shared: bool,
+    handler: impl FnMut() -> IrqReturn + Send + 'static,
+) -> Result<IrqHandle, IrqError> {
+    install(
+        vector,
+        name,
+        shared,
+        Callback::Closure(Mutex::new(Box::new(handler))),
+    )
+}
+
+fn install(
+    vector: u8,
+    name: &'static str,
+    shared: bool,
+    callback: Callback,
+) -> Result<IrqHandle, IrqError> {
+    let index = index(vector)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let action = Arc::new(Action { id, name, callback });
+
+    without_interrupts(|| {
+        let mut entry = VECTORS[index].lock();
+        if !entry.actions.is_empty() && !(entry.shared && shared) {
+            return Err(IrqError::Busy);
+        }
+
+        entry
+            .actions
+            .try_push(action)
+            .map_err(|_| IrqError::NoSpace)?;
+        entry.shared = shared;
+        Ok(())
+    })?;
+
+    debug!("Installed IRQ handler {:?} on vector {:#04x}", name, vector);
+    Ok(IrqHandle { vector, id })
+}
+
+/// Removes a handler again, like dropping its handle does. The vector becomes free once its last
+/// handler is gone, unless it is still claimed through [`allocate_vector`].
+///
+/// A handler may free itself or other handlers on its own vector. It isn't called again
+/// afterwards, but if it is running on another CPU right now, that call still finishes. Freeing
+/// with interrupts disabled only queues the handler, it is dropped by the next [`free`] with
+/// interrupts enabled.
+pub fn free(handle: IrqHandle) {
+    drop(handle);
+}
+
+fn remove(vector: u8, id: u64) {
+    let index = (vector - FIRST_VECTOR) as usize;
+
+    // dropping a closure could take a while, so it happens after interrupts are back on
+    let action = without_interrupts(|| {
+        let mut entry = VECTORS[index].lock();
+        let position = entry.actions.iter().position(|action| action.id == id)?;
+        let action = entry.actions.remove(position);
+        if entry.actions.is_empty() {
+            entry.shared = false;
+        }
+        Some(action)
+    });
+
+    let Some(action) = action else {
+        warn!(
+            "IRQ handler {} on vector {:#04x} was already removed",
+            id, vector
+        );
+        return;
+    };
+    debug!(
+        "Removed IRQ handler {:?} from vector {:#04x}",
+        action.name, vector
+    );
+    retire(action);
+}
+
+/// Drops a removed handler once no CPU runs it anymore.
+///
+/// [`dispatch`] may still hold a reference, and whoever drops the last one frees the closure, which
+/// must not happen in interrupt context where the heap lock could already be held. So the
+/// dispatcher never drops the last reference, and with interrupts disabled the handler is queued.
+fn retire(action: Arc<Action>) {
+    if !are_enabled() {
+        without_interrupts(|| {
+            if let Err(error) = RETIRED.lock().try_push(action) {
+                let action = error.element();
+                warn!(
+                    "Leaking IRQ handler {:?}, too many are waiting to be dropped",
+                    action.name
+                );
+                core::mem::forget(action);
+            }
+        });
+        return;
+    }
+
+    let retired = without_interrupts(|| core::mem::take(&mut *RETIRED.lock()));
+    for action in retired.into_iter().chain(Some(action)) {
+        while Arc::strong_count(&action) > 1 {
+            spin_loop();
+        }
+        drop(action);
+    }
+}
+
+pub fn stats(vector: u8) -> IrqStats {
+    let Some(index) = (vector as usize).checked_sub(FIRST_VECTOR as usize) else {
+        return IrqStats::default();
+    };
+    IrqStats {
+        count: COUNTS[index].load(Ordering::Relaxed),
+        unhandled: UNHANDLED[index].load(Ordering::Relaxed),
+    }
+}
+
+extern "C" fn dispatch(vector: u64) {
+    let vector = vector as u8;
+    let index = (vector - FIRST_VECTOR) as usize;
+    COUNTS[index].fetch_add(1, Ordering::Relaxed);
+
+    // spurious interrupts aren't in service, so there's nothing to acknowledge
+    if vector == SPURIOUS_VECTOR {
+        UNHANDLED[index].fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let stats = percpu!(stats);
+    stats.interrupts.set(stats.interrupts.get() + 1);
+    let depth = percpu!(irq_depth);
+    depth.set(depth.get() + 1);
+
+    // the handlers run without the lock, so they can request and free handlers on their own
+    // vector
+    let actions = VECTORS[index].lock().actions.clone();
+    let handled = actions
+        .iter()
+        .any(|action| action.callback.call() == IrqReturn::Handled);
+    if !handled {
+        UNHANDLED[index].fetch_add(1, Ordering::Relaxed);
+        stats
+            .unhandled_interrupts
+            .set(stats.unhandled_interrupts.get() + 1);
+    }
+    depth.set(depth.get() - 1);
+
+    super::controller::end_of_interrupt(vector);
+}
+
+/// Saves the registers the C ABI doesn't preserve, calls [`dispatch`] with the vector the stub
+/// pushed and returns from the interrupt.
+#[unsafe(naked)]
+extern "C" fn irq_common() {
+    naked_asm!(
+        "push rax",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "mov rdi, [rsp + 72]",
+        // 5 qwords from the CPU, the vector and 9 registers, so the stack is 8 bytes off
+        "sub rsp, 8",
+        "cld",
+        "call {dispatch}",
+        "add rsp, 8",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rax",
+        // vector
+        "add rsp, 8",
+        "iretq",
+        dispatch = sym dispatch,
+    );
+}
+
+// One stub of `STUB_SIZE` bytes for every vector from `FIRST_VECTOR` on, each pushing its
+// vector and jumping to `irq_common`.
+global_asm!(
+    ".pushsection .text.irq_stubs, \"ax\"",
+    ".balign {stub_size}",
+    "IRQ_STUB_TABLE:",
+    ".set irq_vector, {first}",
+    ".rept {count}",
+    ".balign {stub_size}",
+    "pushq $irq_vector",
+    "jmp {common}",
+    ".set irq_vector, irq_vector + 1",
+    ".endr",
+    ".popsection",
+    stub_size = const STUB_SIZE,
+    first = const FIRST_VECTOR,
+    count = const VECTOR_COUNT,
+    common = sym irq_common,
+    options(att_syntax),
+);
+
+unsafe extern "C" {
+    static IRQ_STUB_TABLE: [u8; VECTOR_COUNT * STUB_SIZE];
+}
+
+/// Points every vector from [`FIRST_VECTOR`] on at its stub.
+pub(super) fn install_stubs(idt: &mut InterruptDescriptorTable) {
+    for (index, vector) in (FIRST_VECTOR..=u8::MAX).enumerate() {
+        let stub = unsafe { IRQ_STUB_TABLE.as_ptr().add(index * STUB_SIZE) };
+        unsafe {
+            idt[vector].set_handler_addr(VirtAddr::from_ptr(stub));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IrqReturn, free, request, request_closure, stats};
+    use core::arch::asm;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    const TEST_VECTOR: u8 = 0xF0;
+
+    static FIRST: AtomicU32 = AtomicU32::new(0);
+
+    fn not_mine(context: *mut ()) -> IrqReturn {
+        let counter = unsafe { &*(context as *const AtomicU32) };
+        counter.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::NotHandled
+    }
+
+    #[test_case]
+    fn shared_vector() {
+        let before = stats(TEST_VECTOR);
+        let first = request(
+            TEST_VECTOR,
+            "test",
+            true,
+            not_mine,
+            &FIRST as *const AtomicU32 as *mut (),
+        )
+        .unwrap();
+
+        unsafe { asm!("int 0xF0") };
+        assert_eq!(FIRST.load(Ordering::Relaxed), 1);
+        assert_eq!(stats(TEST_VECTOR).unhandled, before.unhandled + 1);
+
+        static SECOND: AtomicU32 = AtomicU32::new(0);
+        let second = request_closure(TEST_VECTOR, "test closure", true, || {
+            SECOND.fetch_add(1, Ordering::Relaxed);
+            IrqReturn::Handled
+        })
+        .unwrap();
+        assert!(
+            request(
+                TEST_VECTOR,
+                "exclusive",
+                false,
+                not_mine,
+                core::ptr::null_mut()
+            )
+            .is_err()
+        );
+
+        unsafe { asm!("int 0xF0") };
+        assert_eq!(FIRST.load(Ordering::Relaxed), 2);
+        assert_eq!(SECOND.load(Ordering::Relaxed), 1);
+        assert_eq!(stats(TEST_VECTOR).count, before.count + 2);
+        assert_eq!(stats(TEST_VECTOR).unhandled, before.unhandled + 1);
+
+        free(first);
+        free(second);
+        unsafe { asm!("int 0xF0") };
+        assert_eq!(FIRST.load(Ordering::Relaxed), 2);
+        assert_eq!(SECOND.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,5 +1,14 @@
+use core::hint::spin_loop;
+
 use log::info;
-use x86_64::instructions::port::PortWrite;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+use crate::acpi::{apic, ioapic};
+use crate::interrupts::InterruptIndex;
+use crate::interrupts::controller::{self, InterruptController};
+use crate::interrupts::irq::{self, IrqReturn};
+
+const PIT_FREQUENCY: u64 = 1_193_182;
 
 pub fn init() {
     unsafe {
@@ -8,5 +17,40 @@ pub fn init() {
         u8::write_to_port(0x40, (DIVISOR & 0xff) as u8);
         u8::write_to_port(0x40, ((DIVISOR >> 8) & 0xff) as u8);
     }
+
+    // the PIT keeps ticking for as long as the kernel runs
+    irq::request_closure(InterruptIndex::Timer.as_u8(), "pit", false, || {
+        print!(".");
+        IrqReturn::Handled
+    })
+    .expect("PIT vector already in use")
+    .leak();
+
+    if controller::current() == InterruptController::Apic {
+        ioapic::route_isa(0, InterruptIndex::Timer.as_u8(), apic::id())
+            .expect("Failed to route the PIT IRQ through the I/O APIC");
+    }
     info!("Initialized PIT");
-}
\ No newline at end of file
+}
+
+/// Busy waits for `micros` microseconds (at most about 54 ms) on channel 2, which works with
+/// interrupts disabled and leaves the channel 0 tick alone.
+pub fn wait_polled(micros: u64) {
+    let count = (PIT_FREQUENCY * micros / 1_000_000).clamp(1, 0xFFFF) as u16;
+    unsafe {
+        // channel 2's gate is bit 0 of port 0x61, bit 1 would connect it to the speaker
+        let control = u8::read_from_port(0x61);
+        u8::write_to_port(0x61, control & !0b11);
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        u8::write_to_port(0x43, 0b1011_0000);
+        u8::write_to_port(0x42, count as u8);
+        u8::write_to_port(0x42, (count >> 8) as u8);
+        u8::write_to_port(0x61, (control & !0b10) | 0b01);
+
+        // bit 5 mirrors channel 2's output, which goes high once the count runs out
+        while u8::read_from_port(0x61) & 0x20 == 0 {
+            spin_loop();
+        }
+        u8::write_to_port(0x61, control);
+    }
+}
@@ -214,3 +214,35 @@ impl<T: 'static> Iterator for LinkedListMutIter<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{RawLinkedList, RawLinkedListNode};
+
+    #[test_case]
+    fn append_then_pop_front_is_fifo() {
+        let mut a = RawLinkedListNode::new(1);
+        let mut b = RawLinkedListNode::new(2);
+        let mut list = RawLinkedList::new();
+        list.append(&mut a);
+        list.append(&mut b);
+
+        assert_eq!(list.pop_front().map(|n| n.value), Some(1));
+        assert_eq!(list.pop_front().map(|n| n.value), Some(2));
+        assert!(list.pop_front().is_none());
+    }
+
+    #[test_case]
+    fn prepend_reverses_order() {
+        let mut a = RawLinkedListNode::new(1);
+        let mut b = RawLinkedListNode::new(2);
+        let mut list = RawLinkedList::new();
+        list.prepend(&mut a);
+        list.prepend(&mut b);
+
+        let mut iter = list.iter();
+        assert_eq!(iter.next().map(|n| n.value), Some(2));
+        assert_eq!(iter.next().map(|n| n.value), Some(1));
+        assert!(iter.next().is_none());
+    }
+}
@@ -1,4 +1,3 @@
-#![feature(abi_x86_interrupt)]
 #![feature(stmt_expr_attributes)]
 #![feature(core_intrinsics)]
 #![feature(trusted_random_access)]
@@ -8,43 +7,129 @@
 #![feature(arbitrary_self_types)]
 #![allow(static_mut_refs)]
 #![allow(internal_features)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 #![no_std] // don't link the Rust standard library
+#![cfg_attr(test, no_main)]
 
-use bootloader_api::BootInfo;
-use log::info;
+extern crate alloc;
+
+use bootloader_api::config::Mapping;
+use bootloader_api::{BootInfo, BootloaderConfig};
+use log::{info, warn};
 
 #[macro_use]
 pub mod debug_utils;
+#[macro_use]
+pub mod percpu;
 
 pub mod acpi;
 pub mod binutil;
+pub mod cmdline;
 pub mod gdt;
+pub mod initrd;
 pub mod interrupts;
 mod klib;
 mod logger;
 pub mod memory;
+pub mod pci;
+pub mod smp;
 pub mod support;
+pub mod testing;
+pub mod time;
+
+/// Virtual address the kernel image is loaded at.
+/// This has to match `KERNEL_BASE` in the runner so gdb can find the kernel's symbols.
+pub const KERNEL_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config.mappings.kernel_base = Mapping::FixedAddress(KERNEL_BASE);
+    // the bootloader leaves the first page unmapped as a guard page
+    config.mappings.kernel_stack = Mapping::FixedAddress(memory::stack::BOOT_STACK_START);
+    config.kernel_stack_size = memory::stack::BOOT_STACK_SIZE;
+    config
+};
 
 pub fn init(boot_info: &'static BootInfo) {
     unsafe { log::set_logger_racy(&logger::LOGGER).expect("Failed to configure logger") };
-    unsafe { log::set_max_level_racy(log::LevelFilter::Trace) };
+    // the command line (and with it the log levels) comes from the initrd
+    initrd::init(boot_info);
+    cmdline::init();
+    logger::configure(cmdline::get("log").unwrap_or(""));
     print!("\x1b[H\x1b[2J");
     info!("Initialized Logger");
+    info!("Initrd contains {} entries", initrd::entries().count());
+    info!("Command line: {:?}", cmdline::raw());
+    info!(
+        "Kernel image at {:?}",
+        logger::LoggedAddress::Virtual(boot_info.kernel_image_offset)
+    );
 
-    gdt::init();
     memory::init(boot_info);
-    memory::allocator::init_heap(memory::mapper(), memory::frame_allocator::frame_allocator())
-        .expect("Failed to initialize heap");
+    let heap_size = cmdline::get("heap")
+        .and_then(cmdline::parse_size)
+        .unwrap_or(memory::allocator::DEFAULT_HEAP_SIZE);
+    memory::allocator::init_heap(
+        memory::mapper(),
+        &mut memory::frame_allocator::frame_allocator(),
+        heap_size,
+    )
+    .expect("Failed to initialize heap");
+    percpu::init(0);
+    gdt::init();
 
     acpi::init(boot_info);
 
     interrupts::init_idt();
-    interrupts::disable_8259_pic();
-    acpi::apic::init();
-    acpi::hpet::init();
-    acpi::pcie::init();
-    interrupts::pit::init();
+    interrupts::controller::init();
+    if cmdline::enabled("hpet")
+        && let Err(error) = acpi::hpet::init()
+    {
+        warn!("No HPET: {:?}", error);
+    }
+    // the Local APIC timer, AML and everything after them want timestamps and timeouts
+    time::init();
+    if interrupts::controller::current() == interrupts::controller::InterruptController::Apic {
+        acpi::apic::timer::init();
+    }
+    if cmdline::enabled("pcie") {
+        acpi::pcie::init();
+    }
+    if cmdline::enabled("aml") {
+        acpi::aml::init();
+    }
+    if cmdline::enabled("pci") {
+        pci::init();
+    }
+    if cmdline::enabled("pit") {
+        interrupts::pit::init();
+    }
+    if cmdline::enabled("smp") {
+        smp::init();
+    }
     x86_64::instructions::interrupts::enable();
 
     info!("Kernel initialized");
 }
+
+#[cfg(test)]
+bootloader_api::entry_point!(test_kernel_main, config = &BOOTLOADER_CONFIG);
+
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    init(boot_info);
+    test_main();
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    testing::test_panic_handler(info)
+}
@@ -1,13 +1,69 @@
+use arrayvec::ArrayVec;
 use core::fmt::{Debug, Formatter};
-use log::{Level, Record};
+use core::str::FromStr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{Level, LevelFilter, Record};
 use x86_64::{PhysAddr, VirtAddr};
 
 pub(super) struct SerialLogger;
 
 pub(super) static LOGGER: SerialLogger = SerialLogger;
-static mut LOGGER_ALIGNMENT: usize = 0;
+// shared by every CPU, a lost update only makes one line less nicely aligned
+static LOGGER_ALIGNMENT: AtomicUsize = AtomicUsize::new(0);
 const LOGGER_ALIGNMENT_LOWER_THRESHOLD: isize = 24;
 
+const MAX_LOG_DIRECTIVES: usize = 16;
+static mut LOG_LEVEL: LevelFilter = LevelFilter::Debug;
+static mut LOG_DIRECTIVES: ArrayVec<(&'static str, LevelFilter), MAX_LOG_DIRECTIVES> =
+    ArrayVec::new_const();
+
+/// Applies a `log=` command line option such as `debug,acpi=trace,memory=off`.
+///
+/// A bare level sets the default, `module=level` overrides it for `kernel::module` and its children.
+pub(super) fn configure(spec: &'static str) {
+    unsafe {
+        for directive in spec.split(',').filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => match LevelFilter::from_str(level) {
+                    Ok(level) => {
+                        if LOG_DIRECTIVES.try_push((module, level)).is_err() {
+                            log::warn!("Too many log directives, ignoring {:?}", directive);
+                        }
+                    }
+                    Err(_) => log::warn!("Invalid log level in {:?}", directive),
+                },
+                None => match LevelFilter::from_str(directive) {
+                    Ok(level) => LOG_LEVEL = level,
+                    Err(_) => log::warn!("Invalid log level {:?}", directive),
+                },
+            }
+        }
+
+        let max = LOG_DIRECTIVES
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(LOG_LEVEL, Ord::max);
+        log::set_max_level_racy(max);
+    }
+}
+
+fn level_for(module_path: &str) -> LevelFilter {
+    let module_path = module_path.strip_prefix("kernel::").unwrap_or(module_path);
+    unsafe {
+        // the most specific matching directive wins
+        LOG_DIRECTIVES
+            .iter()
+            .filter(|(module, _)| {
+                module_path
+                    .strip_prefix(module)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(LOG_LEVEL)
+    }
+}
+
 fn colorfor(level: log::Level) -> &'static str {
     match level {
         Level::Error => "\x1b[0;91m",
@@ -20,26 +76,35 @@ fn colorfor(level: log::Level) -> &'static str {
 
 impl log::Log for SerialLogger {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= log::Level::Debug
+        metadata.level() <= level_for(metadata.target())
     }
 
     fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
         let module_path = record.module_path().unwrap_or("<unknown>");
-        let alignment = unsafe {
+        let previous = LOGGER_ALIGNMENT.load(Ordering::Relaxed) as isize;
+        let alignment = {
             let thisalign = module_path.len() as isize;
-            let shift = thisalign - LOGGER_ALIGNMENT as isize;
+            let shift = thisalign - previous;
             if shift > 0 {
                 thisalign
             } else if -shift > LOGGER_ALIGNMENT_LOWER_THRESHOLD {
                 thisalign
             } else {
-                LOGGER_ALIGNMENT as isize
+                previous
             }
-        }.max(0) as usize;
-        unsafe { LOGGER_ALIGNMENT = alignment; }
+        }
+        .max(0) as usize;
+        LOGGER_ALIGNMENT.store(alignment, Ordering::Relaxed);
 
+        let nanos = crate::time::monotonic_nanos();
         println!(
-            "\x1b[0;2;37m[{level_color}\x1b[1m{level:<5}\x1b[0;2;37m]\x1b[0;1;97m {module_path:<alignment$} \x1b[0;2;37m>\x1b[0;97m {args}\x1b[0m",
+            "\x1b[0;2;37m[{secs:>5}.{micros:06}] [{level_color}\x1b[1m{level:<5}\x1b[0;2;37m]\x1b[0;1;97m {module_path:<alignment$} \x1b[0;2;37m>\x1b[0;97m {args}\x1b[0m",
+            secs = nanos / 1_000_000_000,
+            micros = nanos / 1000 % 1_000_000,
             module_path = module_path,
             level_color = colorfor(record.level()),
             level = record.level(),
@@ -73,7 +138,10 @@ pub trait IntoLoggedAddress {
     fn into_log(self) -> LoggedAddress;
 }
 
-impl<T> IntoLoggedAddress for T where T: Into<LoggedAddress> {
+impl<T> IntoLoggedAddress for T
+where
+    T: Into<LoggedAddress>,
+{
     fn into_log(self) -> LoggedAddress {
         self.into()
     }
@@ -89,4 +157,4 @@ impl Into<LoggedAddress> for PhysAddr {
     fn into(self) -> LoggedAddress {
         LoggedAddress::Physical(self.as_u64())
     }
-}
\ No newline at end of file
+}
@@ -7,20 +7,13 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
-use kernel::{debug_utils::SERIAL, init, println};
+use kernel::{BOOTLOADER_CONFIG, debug_utils::SERIAL, init, println};
 
-use bootloader_api::config::Mapping;
-use bootloader_api::{BootInfo, BootloaderConfig, entry_point};
+use bootloader_api::{BootInfo, entry_point};
 use core::fmt::Write;
 use core::intrinsics::volatile_store;
 use x86_64::instructions::hlt;
 
-pub static BOOTLOADER_CONFIG: BootloaderConfig = {
-    let mut config = BootloaderConfig::new_default();
-    config.mappings.physical_memory = Some(Mapping::Dynamic);
-    config
-};
-
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
@@ -1,5 +1,7 @@
 pub mod allocator;
 pub mod frame_allocator;
+pub mod stack;
+pub mod tlb;
 
 use crate::logger::{IntoLoggedAddress, LoggedAddress};
 use crate::memory::frame_allocator::boot_info::BootInfoFrameAllocator;
@@ -9,6 +11,8 @@ use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use core::iter::TrustedRandomAccessNoCoerce;
 use core::ops::Range;
 use log::{debug, info, trace, warn};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::paging::frame::{PhysFrameRange, PhysFrameRangeInclusive};
 use x86_64::structures::paging::mapper::{CleanUp, MapToError, MapperFlush};
 use x86_64::structures::paging::page_table::PageTableLevel;
@@ -20,7 +24,7 @@ use x86_64::{PhysAddr, VirtAddr};
 
 static mut PHYSICAL_OFFSET: VirtAddr = VirtAddr::new(0);
 static mut PAGE_TABLE: Option<OffsetPageTable<'static>> = None;
-static mut FRAME_ALLOCATOR: Option<BootInfoFrameAllocator> = None;
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
 
 // This is an invalid address since this is not a canonical address and has enough free space in its address space to withstand an offset without an integer overflow.
 // This address should theoretically never be mapped which is the only reason this is safe to use as an error.
@@ -40,12 +44,64 @@ pub fn init(boot_info: &'static BootInfo) {
             active_level_4_table(PHYSICAL_OFFSET),
             PHYSICAL_OFFSET,
         ));
-        FRAME_ALLOCATOR = Some(BootInfoFrameAllocator::init(&boot_info.memory_regions));
+        *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator::init(&boot_info.memory_regions));
         info!("Initialized Page Table");
         // <dyn Mapper<Size2MiB>>::map_to(PAGE_TABLE.as_mut().unwrap_unchecked(), Page::containing_address(VirtAddr::new(0)), PhysFrame::containing_address());
     }
 }
 
+/// Whether the physical address `addr` is RAM the bootloader marked as usable.
+pub fn is_usable_ram(addr: u64) -> bool {
+    without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .unwrap()
+            .is_usable(PhysAddr::new(addr))
+    })
+}
+
+/// Unmaps every 4 KiB page in `range` and invalidates them on every CPU.
+///
+/// The frames aren't given back to the frame allocator.
+pub fn unmap_region(range: Range<VirtAddr>) {
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(range.start),
+        Page::containing_address(range.end - 1u64) + 1,
+    );
+    for page in pages {
+        match mapper().unmap(page) {
+            Ok((_frame, flush)) => flush.ignore(),
+            Err(e) => warn!(
+                "Not unmapping page at {:?}: {:?}",
+                page.start_address().into_log(),
+                e
+            ),
+        }
+    }
+    tlb::shootdown(range);
+}
+
+/// Changes the flags of every 4 KiB page in `range` and invalidates them on every CPU, so no
+/// CPU keeps using a stale (e.g. still writable) translation.
+pub fn protect_region(range: Range<VirtAddr>, flags: PageTableFlags) {
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(range.start),
+        Page::containing_address(range.end - 1u64) + 1,
+    );
+    for page in pages {
+        match unsafe { mapper().update_flags(page, flags) } {
+            Ok(flush) => flush.ignore(),
+            Err(e) => warn!(
+                "Not changing flags of page at {:?}: {:?}",
+                page.start_address().into_log(),
+                e
+            ),
+        }
+    }
+    tlb::shootdown(range);
+}
+
 // This function is unsafe because it expects that it is safe to remove any page mapping in the given range.
 pub unsafe fn force_map_region(start: VirtAddr, physical_range: Range<u64>) {
     unsafe {
@@ -76,7 +132,7 @@ pub fn map_region(start: VirtAddr, physical_range: Range<u64>) {
                     page,
                     frame,
                     PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::HUGE_PAGE,
-                    frame_allocator::frame_allocator(),
+                    &mut frame_allocator::frame_allocator(),
                 ) {
                     Ok(mapped_frame) => {
                         trace!(
@@ -122,7 +178,7 @@ pub fn map_region(start: VirtAddr, physical_range: Range<u64>) {
                     page,
                     frame,
                     PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::HUGE_PAGE,
-                    frame_allocator::frame_allocator(),
+                    &mut frame_allocator::frame_allocator(),
                 ) {
                     Ok(mapped_frame) => {
                         trace!(
@@ -139,6 +195,24 @@ pub fn map_region(start: VirtAddr, physical_range: Range<u64>) {
 }
 
 pub fn map_identity(range: Range<u64>) -> PhysFrameRangeInclusive {
+    map_identity_with_flags(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+}
+
+/// Identity maps device memory in `range` with caching disabled and returns its address.
+///
+/// Pages that were already identity mapped (e.g. by an earlier [`map_identity`]) are made
+/// uncached as well.
+pub fn map_mmio(range: Range<u64>) -> VirtAddr {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    map_identity_with_flags(range.clone(), flags);
+    protect_region(VirtAddr::new(range.start)..VirtAddr::new(range.end), flags);
+    VirtAddr::new(range.start)
+}
+
+fn map_identity_with_flags(range: Range<u64>, flags: PageTableFlags) -> PhysFrameRangeInclusive {
     let range = PhysFrame::range_inclusive(
         PhysFrame::containing_address(PhysAddr::new(range.start)),
         PhysFrame::containing_address(PhysAddr::new(range.end - 1)),
@@ -151,11 +225,11 @@ pub fn map_identity(range: Range<u64>) -> PhysFrameRangeInclusive {
 
     for frame in range {
         unsafe {
-            match PAGE_TABLE.as_mut().unwrap().identity_map(
-                frame,
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                FRAME_ALLOCATOR.as_mut().unwrap(),
-            ) {
+            match PAGE_TABLE
+                .as_mut()
+                .unwrap()
+                .identity_map(frame, flags, &mut frame_allocator())
+            {
                 Ok(mapped_frame) => {
                     trace!(
                         "Mapping 4KiB at {:?} -> {:?}",
@@ -201,7 +275,7 @@ pub fn map_frame_identity(frame: PhysFrame) -> Page {
         let res = mapper().identity_map(
             frame,
             PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-            frame_allocator(),
+            &mut frame_allocator(),
         );
         let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
         match res {
@@ -220,7 +294,6 @@ pub fn map_frame_identity(frame: PhysFrame) -> Page {
             }
         }
 
-
         page
     }
 }
@@ -1,4 +1,5 @@
 use crate::klib::linked_list::{RawLinkedList, RawLinkedListNode};
+use crate::logger::IntoLoggedAddress;
 use crate::memory::FRAME_ALLOCATOR;
 use crate::memory::allocator::paged_pool::PoolAllocator;
 use crate::memory::frame_allocator::frame_allocator;
@@ -8,8 +9,9 @@ use bootloader_api::info::MemoryRegionKind;
 use core::cmp::min;
 use log::trace;
 use x86_64::PhysAddr;
-use x86_64::structures::paging::{FrameAllocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB};
-use crate::logger::IntoLoggedAddress;
+use x86_64::structures::paging::{
+    FrameAllocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
 
 #[repr(C)]
 pub struct Block {
@@ -63,9 +65,9 @@ impl Block {
 
     unsafe fn split(&mut self, node_allocator: &mut PoolAllocator<Block>) {
         unsafe {
-            let mut left = node_allocator.alloc(frame_allocator());
+            let mut left = node_allocator.alloc(&mut frame_allocator());
             left.set_values(self.block_ptr, self.size - 1, self.flags);
-            let mut right = node_allocator.alloc(frame_allocator());
+            let mut right = node_allocator.alloc(&mut frame_allocator());
             right.set_values(
                 self.block_ptr.offset(1 << (self.size - 1)),
                 self.size - 1,
@@ -174,11 +176,13 @@ pub struct BuddyAllocator {
 impl BuddyAllocator {
     pub fn new(boot_info: &'static BootInfo) -> Self {
         let mut blocks: RawLinkedList<Block> = RawLinkedList::new();
-        let mut node_source: PoolAllocator<Block> = PoolAllocator::new(frame_allocator());
+        let mut node_source: PoolAllocator<Block> = PoolAllocator::new(&mut frame_allocator());
 
-        let first_open_frame = unsafe { FRAME_ALLOCATOR.as_mut().unwrap_unchecked() }
+        // a copy, since the node pool allocates its pages from the real one
+        let boot_allocator = FRAME_ALLOCATOR.lock().as_ref().unwrap().clone();
+        let first_open_frame = boot_allocator
             .usable_frames()
-            .nth(unsafe { FRAME_ALLOCATOR.as_ref().unwrap_unchecked().num_used() })
+            .nth(boot_allocator.num_used())
             .map(|f| f.start_address())
             .unwrap_or(PhysAddr::new(0));
 
@@ -195,21 +199,21 @@ impl BuddyAllocator {
                     // aligned to 1 GiB
 
                     if region.end - cursor >= BUDDYALLOC_MAX_SIZE {
-                        let mut block_node = node_source.alloc(frame_allocator());
+                        let mut block_node = node_source.alloc(&mut frame_allocator());
                         block_node.set_values(cursor as *mut u8, 30, BlockFlags::empty());
                         cursor += 1 << 30;
                     }
                 } else if cursor & 0x1fffff == 0 {
                     // aligned to 2MiB
                     if region.end - cursor >= 1 << 21 {
-                        let mut block_node = node_source.alloc(frame_allocator());
+                        let mut block_node = node_source.alloc(&mut frame_allocator());
                         block_node.set_values(cursor as *mut u8, 21, BlockFlags::empty());
                         cursor += 1 << 21;
                     }
                 } else if cursor & 0xfff == 0 {
                     // aligned to 4KiB
                     if region.end - cursor >= 1 << 12 {
-                        let mut block_node = node_source.alloc(frame_allocator());
+                        let mut block_node = node_source.alloc(&mut frame_allocator());
                         block_node.set_values(cursor as *mut u8, 12, BlockFlags::empty());
                         cursor += 1 << 12;
                     }
@@ -218,11 +222,9 @@ impl BuddyAllocator {
         }
 
         let mut counter = 0;
-        for frame in unsafe { FRAME_ALLOCATOR.as_ref().unwrap().usable_frames() } {
-            unsafe {
-                if counter > FRAME_ALLOCATOR.as_ref().unwrap().num_used() {
-                    break;
-                }
+        for frame in boot_allocator.usable_frames() {
+            if counter > FRAME_ALLOCATOR.lock().as_ref().unwrap().num_used() {
+                break;
             }
 
             for block in blocks.iter_mut() {
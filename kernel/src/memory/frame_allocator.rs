@@ -1,11 +1,20 @@
 pub mod boot_info;
 pub mod general_purpose;
 
-use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
 use crate::memory::FRAME_ALLOCATOR;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
 
-pub fn frame_allocator() -> &'static mut impl FrameAllocator<Size4KiB> {
-    unsafe { FRAME_ALLOCATOR.as_mut().unwrap() }
-}
+/// Hands out frames from the global frame allocator, which is locked for every frame so any CPU
+/// can use it.
+pub struct LockedFrameAllocator;
 
+unsafe impl FrameAllocator<Size4KiB> for LockedFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame())
+    }
+}
 
+pub fn frame_allocator() -> LockedFrameAllocator {
+    LockedFrameAllocator
+}
@@ -2,7 +2,11 @@ use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use x86_64::PhysAddr;
 use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
 
+/// Frames below this are never handed out, they are kept for real mode code like the AP trampoline.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
 // A FrameAllocator that returns usable frames from the bootloader's memory map.
+#[derive(Clone)]
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryRegions,
     next: usize,
@@ -27,13 +31,20 @@ impl BootInfoFrameAllocator {
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
         // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
+        let addr_ranges = usable_regions.map(|r| r.start.max(LOW_MEMORY_END)..r.end);
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 
+    /// Whether `addr` is in a region the bootloader marked as usable.
+    pub fn is_usable(&self, addr: PhysAddr) -> bool {
+        self.memory_map.iter().any(|r| {
+            r.kind == MemoryRegionKind::Usable && (r.start..r.end).contains(&addr.as_u64())
+        })
+    }
+
     pub fn num_used(&self) -> usize {
         self.next
     }
@@ -46,4 +57,3 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
-
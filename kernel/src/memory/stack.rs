@@ -0,0 +1,82 @@
+use arrayvec::ArrayVec;
+use log::debug;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+
+use crate::logger::IntoLoggedAddress;
+use crate::memory::frame_allocator::frame_allocator;
+use crate::memory::mapper;
+
+/// The bootloader leaves this page unmapped as a guard page and maps the boot stack above it.
+pub const BOOT_STACK_START: u64 = 0x_5555_0000_0000;
+pub const BOOT_STACK_SIZE: u64 = 128 * 1024;
+
+// Stacks allocated by the kernel (e.g. for the IST) live here, each with an unmapped guard page below.
+const STACK_REGION_START: u64 = 0x_5555_1000_0000;
+// every CPU has its 3 IST stacks, and every AP also the stack it starts on
+const MAX_STACKS: usize = 4 * crate::smp::MAX_CPUS;
+
+struct Stack {
+    name: &'static str,
+    guard_page: u64,
+}
+
+static mut NEXT_STACK: u64 = STACK_REGION_START;
+static mut STACKS: ArrayVec<Stack, MAX_STACKS> = ArrayVec::new_const();
+
+/// Maps a new stack of `size` bytes (rounded up to whole pages) and returns its top.
+///
+/// The page below the stack stays unmapped, so overflowing it page faults instead of silently
+/// overwriting whatever is below.
+pub fn allocate(name: &'static str, size: u64) -> VirtAddr {
+    let size = size.div_ceil(Page::<Size4KiB>::SIZE) * Page::<Size4KiB>::SIZE;
+    unsafe {
+        let guard_page = NEXT_STACK;
+        let bottom = guard_page + Page::<Size4KiB>::SIZE;
+        let top = bottom + size;
+        NEXT_STACK = top;
+
+        STACKS
+            .try_push(Stack { name, guard_page })
+            .expect("Too many kernel stacks");
+
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(VirtAddr::new(bottom)),
+            Page::containing_address(VirtAddr::new(top)),
+        );
+        for page in pages {
+            let frame = frame_allocator()
+                .allocate_frame()
+                .expect("Failed to allocate frame for kernel stack");
+            mapper()
+                .map_to(
+                    page,
+                    frame,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                    &mut frame_allocator(),
+                )
+                .expect("Failed to map kernel stack")
+                .flush();
+        }
+
+        debug!(
+            "Allocated {} stack at {:?}..{:?}",
+            name,
+            VirtAddr::new(bottom).into_log(),
+            VirtAddr::new(top).into_log()
+        );
+        VirtAddr::new(top)
+    }
+}
+
+/// Returns the name of the stack whose guard page contains `addr`.
+pub fn guard_page_owner(addr: u64) -> Option<&'static str> {
+    let page = addr & !(Page::<Size4KiB>::SIZE - 1);
+    if page == BOOT_STACK_START {
+        return Some("boot");
+    }
+
+    unsafe { STACKS.iter() }
+        .find(|stack| stack.guard_page == page)
+        .map(|stack| stack.name)
+}
@@ -0,0 +1,107 @@
+use core::hint::spin_loop;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::VirtAddr;
+use x86_64::instructions::tlb;
+
+use crate::interrupts::irq::{self, IrqReturn};
+use crate::smp::{self, ipi};
+
+/// Vector the shootdown IPI arrives on.
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xFD;
+
+/// Above this many pages the whole TLB is flushed instead of single pages.
+const MAX_INVLPG_PAGES: u64 = 32;
+const PAGE_SIZE: u64 = 4096;
+
+// One shootdown at a time: the range goes to `REQUEST` and every other online CPU gets a bit in
+// `PENDING`, which it clears once it flushed the range.
+static LOCK: AtomicBool = AtomicBool::new(false);
+static mut REQUEST: Range<u64> = 0..0;
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Installs the handler for the shootdown IPI, before the APs are started.
+pub(crate) fn init() {
+    irq::request(
+        TLB_SHOOTDOWN_VECTOR,
+        "tlb shootdown",
+        false,
+        handle_ipi,
+        core::ptr::null_mut(),
+    )
+    .expect("TLB shootdown vector already in use")
+    .leak();
+}
+
+fn flush_local(range: Range<u64>) {
+    let pages = range.end.div_ceil(PAGE_SIZE) - range.start / PAGE_SIZE;
+    if pages > MAX_INVLPG_PAGES {
+        tlb::flush_all();
+        return;
+    }
+
+    for page in (range.start & !(PAGE_SIZE - 1)..range.end).step_by(PAGE_SIZE as usize) {
+        tlb::flush(VirtAddr::new(page));
+    }
+}
+
+/// Invalidates `range` in the TLB of every online CPU and returns once all of them did.
+///
+/// Needed whenever a mapping is removed or loses permissions. New mappings can't be cached
+/// anywhere yet, so flushing the local TLB is enough for them.
+pub fn shootdown(range: Range<VirtAddr>) {
+    let range = range.start.as_u64()..range.end.as_u64();
+    flush_local(range.clone());
+
+    let cpus = smp::cpu_count();
+    if cpus == 1 {
+        return;
+    }
+
+    // The CPU holding the lock could be waiting on this one, maybe with interrupts disabled, so
+    // its request is served while waiting for the lock.
+    while LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        serve();
+        spin_loop();
+    }
+
+    let online = if cpus == 64 {
+        u64::MAX
+    } else {
+        (1 << cpus) - 1
+    };
+    unsafe {
+        REQUEST = range;
+    }
+    PENDING.store(online & !(1 << smp::current_cpu()), Ordering::Release);
+    ipi::send(
+        ipi::Destination::AllButSelf,
+        ipi::Delivery::Fixed(TLB_SHOOTDOWN_VECTOR),
+    );
+
+    while PENDING.load(Ordering::Acquire) != 0 {
+        spin_loop();
+    }
+    LOCK.store(false, Ordering::Release);
+}
+
+/// Flushes the range of the shootdown in progress, if the current CPU still has to.
+///
+/// Whoever spins on a lock with interrupts disabled should call this while waiting, in case the
+/// lock holder is waiting for this CPU's shootdown.
+pub(crate) fn serve() {
+    let bit = 1 << smp::current_cpu();
+    if PENDING.load(Ordering::Acquire) & bit != 0 {
+        flush_local(unsafe { REQUEST.clone() });
+        PENDING.fetch_and(!bit, Ordering::AcqRel);
+    }
+}
+
+fn handle_ipi(_context: *mut ()) -> IrqReturn {
+    serve();
+    IrqReturn::Handled
+}
@@ -0,0 +1,364 @@
+pub mod bar;
+pub mod capability;
+pub mod msi;
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use log::{debug, info, warn};
+
+use crate::acpi::pcie;
+use bar::{Bar, MAX_BARS};
+
+const MAX_BUSES: usize = 256;
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+const REG_VENDOR_ID: u16 = 0x00;
+const REG_DEVICE_ID: u16 = 0x02;
+const REG_CLASS: u16 = 0x08;
+const REG_HEADER_TYPE: u16 = 0x0E;
+const REG_SECONDARY_BUS: u16 = 0x19;
+
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+const SUBCLASS_CARDBUS_BRIDGE: u8 = 0x07;
+
+/// Where a function is in configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl From<::acpi::PciAddress> for PciAddress {
+    fn from(address: ::acpi::PciAddress) -> Self {
+        Self::new(
+            address.segment(),
+            address.bus(),
+            address.device(),
+            address.function(),
+        )
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    Endpoint,
+    PciBridge,
+    CardBusBridge,
+    Unknown(u8),
+}
+
+/// A single function found while scanning the buses.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: HeaderType,
+    pub multifunction: bool,
+    /// Sized once while scanning, since sizing briefly turns off decoding. Indexed by BAR, the
+    /// upper halves of 64 bit BARs are `None`.
+    decoded_bars: [Option<Bar>; MAX_BARS],
+}
+
+static mut DEVICES: Vec<PciDevice> = Vec::new();
+
+pub fn config_read_u8(address: PciAddress, offset: u16) -> u8 {
+    pcie::read_u8(address, offset)
+}
+
+pub fn config_read_u16(address: PciAddress, offset: u16) -> u16 {
+    pcie::read_u16(address, offset)
+}
+
+pub fn config_read_u32(address: PciAddress, offset: u16) -> u32 {
+    pcie::read_u32(address, offset)
+}
+
+pub fn config_write_u8(address: PciAddress, offset: u16, value: u8) {
+    pcie::write_u8(address, offset, value);
+}
+
+pub fn config_write_u16(address: PciAddress, offset: u16, value: u16) {
+    pcie::write_u16(address, offset, value);
+}
+
+pub fn config_write_u32(address: PciAddress, offset: u16, value: u32) {
+    pcie::write_u32(address, offset, value);
+}
+
+/// Scans every bus reachable from the host bridges and logs what it finds, like `lspci` would.
+///
+/// Every segment group with an ECAM window is scanned, so [`pcie::init`] should run first if
+/// there is an MCFG. Otherwise only segment 0 is reachable, through the legacy ports.
+pub fn init() {
+    let mut devices = Vec::new();
+    let mut roots = pcie::root_buses().peekable();
+    if roots.peek().is_none() {
+        Scanner::new(0, &mut devices).scan_root(0);
+    }
+    for (segment, bus) in roots {
+        Scanner::new(segment, &mut devices).scan_root(bus);
+    }
+
+    info!("Found {} PCI functions", devices.len());
+    for device in devices.iter() {
+        info!("{}", device);
+    }
+    bar::assign_unassigned(&mut devices);
+    for device in devices.iter() {
+        for bar in device.bars() {
+            debug!("{} {:x?}", device.address, bar);
+        }
+        for capability in device.capabilities() {
+            debug!(
+                "{} capability {:#04x} at {:#x}",
+                device.address, capability.id, capability.offset
+            );
+        }
+        for capability in device.extended_capabilities() {
+            debug!(
+                "{} extended capability {:#06x} v{} at {:#x}",
+                device.address, capability.id, capability.version, capability.offset
+            );
+        }
+    }
+    unsafe {
+        DEVICES = devices;
+    }
+}
+
+struct Scanner<'a> {
+    segment: u16,
+    devices: &'a mut Vec<PciDevice>,
+    // broken firmware could make bridges point at each other
+    scanned: [bool; MAX_BUSES],
+}
+
+impl<'a> Scanner<'a> {
+    fn new(segment: u16, devices: &'a mut Vec<PciDevice>) -> Self {
+        Self {
+            segment,
+            devices,
+            scanned: [false; MAX_BUSES],
+        }
+    }
+
+    /// Scans the bus behind the host bridge at `bus`:00.0, or one bus per function if it's a
+    /// multifunction device.
+    fn scan_root(&mut self, bus: u8) {
+        let root = PciAddress::new(self.segment, bus, 0, 0);
+        if config_read_u8(root, REG_HEADER_TYPE) & HEADER_MULTIFUNCTION == 0 {
+            self.scan_bus(bus);
+            return;
+        }
+
+        for function in 0..FUNCTIONS_PER_DEVICE {
+            let host_bridge = PciAddress::new(self.segment, bus, 0, function);
+            if config_read_u16(host_bridge, REG_VENDOR_ID) != 0xFFFF {
+                self.scan_bus(bus.wrapping_add(function));
+            }
+        }
+    }
+
+    fn scan_bus(&mut self, bus: u8) {
+        if core::mem::replace(&mut self.scanned[bus as usize], true) {
+            warn!(
+                "PCI bus {:04x}:{:02x} is behind more than one bridge",
+                self.segment, bus
+            );
+            return;
+        }
+
+        for device in 0..DEVICES_PER_BUS {
+            self.scan_device(bus, device);
+        }
+    }
+
+    fn scan_device(&mut self, bus: u8, device: u8) {
+        let Some(first) = probe(PciAddress::new(self.segment, bus, device, 0)) else {
+            return;
+        };
+        self.add(first);
+
+        if first.multifunction {
+            for function in 1..FUNCTIONS_PER_DEVICE {
+                let address = PciAddress::new(self.segment, bus, device, function);
+                if let Some(function) = probe(address) {
+                    self.add(function);
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, device: PciDevice) {
+        self.devices.push(device);
+        if device.header_type == HeaderType::PciBridge {
+            let secondary = config_read_u8(device.address, REG_SECONDARY_BUS);
+            debug!(
+                "PCI bridge {} leads to bus {:02x}",
+                device.address, secondary
+            );
+            // an unconfigured bridge still has 0 here
+            if secondary != 0 {
+                self.scan_bus(secondary);
+            }
+        }
+    }
+}
+
+/// Reads the identification of the function at `address`, if there is one.
+fn probe(address: PciAddress) -> Option<PciDevice> {
+    let vendor_id = config_read_u16(address, REG_VENDOR_ID);
+    if vendor_id == 0xFFFF {
+        return None;
+    }
+
+    let class = config_read_u32(address, REG_CLASS);
+    let header = config_read_u8(address, REG_HEADER_TYPE);
+    let mut device = PciDevice {
+        address,
+        vendor_id,
+        device_id: config_read_u16(address, REG_DEVICE_ID),
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        header_type: match header & HEADER_TYPE_MASK {
+            0x00 => HeaderType::Endpoint,
+            0x01 => HeaderType::PciBridge,
+            0x02 => HeaderType::CardBusBridge,
+            other => HeaderType::Unknown(other),
+        },
+        multifunction: header & HEADER_MULTIFUNCTION != 0,
+        decoded_bars: [None; MAX_BARS],
+    };
+    device.size_bars();
+    Some(device)
+}
+
+/// Every function found by [`init`].
+pub fn devices() -> &'static [PciDevice] {
+    unsafe { DEVICES.as_slice() }
+}
+
+pub fn find_by_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.class == class && device.subclass == subclass)
+}
+
+pub fn find_by_id(vendor_id: u16, device_id: u16) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+impl PciDevice {
+    /// A human readable name for the class, as far as it is known.
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x00) => "SCSI storage controller",
+            (0x01, 0x01) => "IDE interface",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "Non-Volatile memory controller",
+            (0x01, _) => "Mass storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "Network controller",
+            (0x03, 0x00) => "VGA compatible controller",
+            (0x03, _) => "Display controller",
+            (0x04, 0x03) => "Audio device",
+            (0x04, _) => "Multimedia controller",
+            (0x05, _) => "Memory controller",
+            (CLASS_BRIDGE, 0x00) => "Host bridge",
+            (CLASS_BRIDGE, 0x01) => "ISA bridge",
+            (CLASS_BRIDGE, SUBCLASS_PCI_BRIDGE) => "PCI bridge",
+            (CLASS_BRIDGE, SUBCLASS_CARDBUS_BRIDGE) => "CardBus bridge",
+            (CLASS_BRIDGE, _) => "Bridge",
+            (0x07, 0x00) => "Serial controller",
+            (0x07, _) => "Communication controller",
+            (0x08, _) => "System peripheral",
+            (0x09, _) => "Input device controller",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus",
+            (0x0C, _) => "Serial bus controller",
+            (0x0D, _) => "Wireless controller",
+            _ => "Unclassified device",
+        }
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})",
+            self.address,
+            self.class_name(),
+            self.class,
+            self.subclass,
+            self.vendor_id,
+            self.device_id,
+            self.revision
+        )?;
+        if self.prog_if != 0 {
+            write!(f, " (prog-if {:02x})", self.prog_if)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn host_bridge_found() {
+        // every PC has a host bridge at 00:00.0
+        let host_bridge = devices()
+            .iter()
+            .find(|device| device.address == PciAddress::new(0, 0, 0, 0))
+            .expect("No device at 00:00.0");
+        assert_eq!(
+            (host_bridge.class, host_bridge.subclass),
+            (CLASS_BRIDGE, 0x00)
+        );
+        assert!(find_by_class(CLASS_BRIDGE, 0x00).count() >= 1);
+        assert!(
+            find_by_id(host_bridge.vendor_id, host_bridge.device_id)
+                .any(|device| device.address == host_bridge.address)
+        );
+    }
+}
@@ -0,0 +1,477 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ops::Range;
+
+use log::{debug, info, warn};
+use x86_64::VirtAddr;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+use super::{
+    HeaderType, PciDevice, config_read_u16, config_read_u32, config_write_u16, config_write_u32,
+};
+use crate::acpi::aml::{self, PciRootBridge, WindowKind};
+use crate::memory;
+
+const REG_COMMAND: u16 = 0x04;
+const REG_BAR0: u16 = 0x10;
+
+pub(super) const COMMAND_IO: u16 = 1 << 0;
+pub(super) const COMMAND_MEMORY: u16 = 1 << 1;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+const BAR_MEMORY_FLAGS: u32 = 0xF;
+const BAR_IO_FLAGS: u32 = 0x3;
+
+const FOUR_GIB: u64 = 1 << 32;
+
+/// How many BARs an endpoint has, bridges have fewer.
+pub const MAX_BARS: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    Memory32,
+    Memory64,
+    Io,
+}
+
+/// A decoded and sized Base Address Register.
+#[derive(Debug, Clone, Copy)]
+pub struct Bar {
+    pub index: u8,
+    pub kind: BarKind,
+    /// The bus address, 0 if the firmware didn't assign one.
+    pub address: u64,
+    pub size: u64,
+    pub prefetchable: bool,
+}
+
+impl Bar {
+    pub fn is_assigned(&self) -> bool {
+        self.address != 0
+    }
+
+    pub fn range(&self) -> Range<u64> {
+        self.address..self.address + self.size
+    }
+
+    fn offset(&self) -> u16 {
+        REG_BAR0 + self.index as u16 * 4
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarError {
+    /// The BAR isn't implemented, or it's the upper half of a 64 bit BAR.
+    NoSuchBar(u8),
+    /// Neither the firmware nor [`super::init`] assigned an address.
+    Unassigned(u8),
+}
+
+/// A mapped memory BAR, accessed with caching disabled.
+pub struct Mmio {
+    base: VirtAddr,
+    size: u64,
+}
+
+impl Mmio {
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn check<T>(&self, offset: u64) {
+        assert!(
+            offset + size_of::<T>() as u64 <= self.size
+                && offset.is_multiple_of(size_of::<T>() as u64),
+            "Invalid MMIO access of {} bytes at {:#x}",
+            size_of::<T>(),
+            offset
+        );
+    }
+
+    /// Reads the register at `offset`, which has to be aligned to the register's size.
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        self.check::<T>(offset);
+        unsafe { core::intrinsics::volatile_load((self.base + offset).as_ptr::<T>()) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: u64, value: T) {
+        self.check::<T>(offset);
+        unsafe { core::intrinsics::volatile_store((self.base + offset).as_mut_ptr::<T>(), value) }
+    }
+}
+
+/// An I/O BAR.
+pub struct IoPorts {
+    base: u16,
+    size: u16,
+}
+
+impl IoPorts {
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn port<T>(&self, offset: u16) -> u16 {
+        assert!(
+            offset as usize + size_of::<T>() <= self.size as usize,
+            "Invalid port access of {} bytes at {:#x}",
+            size_of::<T>(),
+            offset
+        );
+        self.base + offset
+    }
+
+    pub fn read<T: PortRead>(&self, offset: u16) -> T {
+        unsafe { T::read_from_port(self.port::<T>(offset)) }
+    }
+
+    pub fn write<T: PortWrite>(&self, offset: u16, value: T) {
+        unsafe { T::write_to_port(self.port::<T>(offset), value) }
+    }
+}
+
+pub enum BarRegion {
+    Memory(Mmio),
+    Io(IoPorts),
+}
+
+impl PciDevice {
+    /// How many BARs the header type has.
+    pub fn bar_count(&self) -> u8 {
+        match self.header_type {
+            HeaderType::Endpoint => MAX_BARS as u8,
+            HeaderType::PciBridge => 2,
+            _ => 0,
+        }
+    }
+
+    /// BAR `index` as it was sized during the scan, with the address [`super::init`] assigned
+    /// if the firmware didn't.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        *self.decoded_bars.get(index as usize)?
+    }
+
+    /// Sizes every BAR and stores them, which only happens once while scanning.
+    pub(super) fn size_bars(&mut self) {
+        let mut index = 0;
+        while index < self.bar_count() {
+            let bar = self.size_bar(index);
+            self.decoded_bars[index as usize] = bar;
+            index += match bar {
+                Some(Bar {
+                    kind: BarKind::Memory64,
+                    ..
+                }) => 2,
+                _ => 1,
+            };
+        }
+    }
+
+    /// Decodes BAR `index` and finds its size by writing all ones to it.
+    ///
+    /// Decoding is turned off while the BAR is sized, so the device doesn't briefly respond at
+    /// whatever address that makes up.
+    fn size_bar(&self, index: u8) -> Option<Bar> {
+        if index >= self.bar_count() {
+            return None;
+        }
+        let offset = REG_BAR0 + index as u16 * 4;
+
+        let command = config_read_u16(self.address, REG_COMMAND);
+        config_write_u16(
+            self.address,
+            REG_COMMAND,
+            command & !(COMMAND_IO | COMMAND_MEMORY),
+        );
+        let low = config_read_u32(self.address, offset);
+        let kind = if low & BAR_IO != 0 {
+            BarKind::Io
+        } else if low & BAR_TYPE_MASK == BAR_TYPE_64 {
+            BarKind::Memory64
+        } else {
+            BarKind::Memory32
+        };
+        let low_mask = self.size_mask(offset, low);
+        // a 64 bit BAR can't start in the last slot
+        let high = (kind == BarKind::Memory64 && index + 1 < self.bar_count())
+            .then(|| config_read_u32(self.address, offset + 4));
+        let high_mask = high.map(|high| self.size_mask(offset + 4, high));
+        config_write_u16(self.address, REG_COMMAND, command);
+
+        // an unimplemented BAR is hardwired to 0
+        if low_mask == 0 || (kind == BarKind::Memory64 && high.is_none()) {
+            return None;
+        }
+
+        let value = (high.unwrap_or(0) as u64) << 32 | low as u64;
+        // 32 bit BARs can't be any bigger, as if the upper half was all ones
+        let mask = (high_mask.unwrap_or(u32::MAX) as u64) << 32 | low_mask as u64;
+        let (address, mask) = match kind {
+            BarKind::Io => {
+                // only the low 16 bits are implemented if the upper ones read back as 0
+                let mask = mask & !(BAR_IO_FLAGS as u64);
+                let mask = if mask & 0xFFFF_0000 == 0 {
+                    mask | 0xFFFF_0000
+                } else {
+                    mask
+                };
+                (value & !(BAR_IO_FLAGS as u64), mask)
+            }
+            _ => (
+                value & !(BAR_MEMORY_FLAGS as u64),
+                mask & !(BAR_MEMORY_FLAGS as u64),
+            ),
+        };
+
+        Some(Bar {
+            index,
+            kind,
+            address,
+            size: (!mask).wrapping_add(1),
+            prefetchable: kind != BarKind::Io && low & BAR_PREFETCHABLE != 0,
+        })
+    }
+
+    fn size_mask(&self, offset: u16, original: u32) -> u32 {
+        config_write_u32(self.address, offset, u32::MAX);
+        let mask = config_read_u32(self.address, offset);
+        config_write_u32(self.address, offset, original);
+        mask
+    }
+
+    /// Every implemented BAR, the upper halves of 64 bit BARs aren't listed separately.
+    pub fn bars(&self) -> Vec<Bar> {
+        self.decoded_bars.iter().flatten().copied().collect()
+    }
+
+    /// Maps BAR `index` and turns on the device's decoding of it.
+    ///
+    /// Memory BARs are identity mapped uncached, I/O BARs only get bounds checked accessors.
+    pub fn map_bar(&self, index: u8) -> Result<BarRegion, BarError> {
+        let bar = self.bar(index).ok_or(BarError::NoSuchBar(index))?;
+        if !bar.is_assigned() {
+            return Err(BarError::Unassigned(index));
+        }
+
+        let command = config_read_u16(self.address, REG_COMMAND);
+        let region = match bar.kind {
+            BarKind::Io => {
+                config_write_u16(self.address, REG_COMMAND, command | COMMAND_IO);
+                BarRegion::Io(IoPorts {
+                    base: bar.address as u16,
+                    size: bar.size as u16,
+                })
+            }
+            BarKind::Memory32 | BarKind::Memory64 => {
+                let base = memory::map_mmio(bar.range());
+                config_write_u16(self.address, REG_COMMAND, command | COMMAND_MEMORY);
+                BarRegion::Memory(Mmio {
+                    base,
+                    size: bar.size,
+                })
+            }
+        };
+        debug!("Mapped BAR {} of {}: {:x?}", index, self.address, bar);
+        Ok(region)
+    }
+}
+
+struct Window {
+    kind: WindowKind,
+    range: Range<u64>,
+}
+
+/// Hands out ranges in a root bridge's windows that don't overlap anything already assigned.
+struct Allocator {
+    windows: Vec<Window>,
+    used: Vec<Range<u64>>,
+}
+
+impl Allocator {
+    fn allocate(&mut self, bar: &Bar) -> Option<u64> {
+        let (kind, limit) = match bar.kind {
+            BarKind::Io => (WindowKind::Io, 0x1_0000),
+            BarKind::Memory32 => (WindowKind::Memory, FOUR_GIB),
+            BarKind::Memory64 => (WindowKind::Memory, u64::MAX),
+        };
+
+        // BARs are naturally aligned, so the size is also the alignment
+        for window in self.windows.iter().filter(|window| window.kind == kind) {
+            let end = window.range.end.min(limit);
+            let mut candidate = window.range.start.next_multiple_of(bar.size);
+            while candidate.saturating_add(bar.size) <= end {
+                let range = candidate..candidate + bar.size;
+                match self
+                    .used
+                    .iter()
+                    .find(|used| used.start < range.end && range.start < used.end)
+                {
+                    Some(used) => candidate = used.end.next_multiple_of(bar.size),
+                    None => {
+                        self.used.push(range);
+                        return Some(candidate);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Gives BARs the firmware left unassigned an address in the `_CRS` windows of their root
+/// bridge.
+///
+/// Only devices directly on a root bus are handled, anything behind a PCI bridge would need the
+/// bridge's windows to be set up as well.
+pub(super) fn assign_unassigned(devices: &mut [PciDevice]) {
+    let bridges = aml::pci_root_bridges();
+    if bridges.is_empty() {
+        debug!("No PCI root bridges in the AML namespace, not assigning BARs");
+        return;
+    }
+
+    for bridge in bridges.iter() {
+        assign_on_root_bus(bridge, devices);
+    }
+}
+
+fn assign_on_root_bus(bridge: &PciRootBridge, devices: &mut [PciDevice]) {
+    let in_segment = |device: &&PciDevice| device.address.segment == bridge.segment;
+    let mut allocator = Allocator {
+        windows: bridge
+            .windows
+            .iter()
+            .map(|window| Window {
+                kind: window.kind,
+                range: window.range.clone(),
+            })
+            .collect(),
+        used: devices
+            .iter()
+            .filter(in_segment)
+            .flat_map(|device| device.bars())
+            .filter(Bar::is_assigned)
+            .map(|bar| bar.range())
+            .collect(),
+    };
+
+    let mut unassigned = devices
+        .iter()
+        .enumerate()
+        .filter(|(_, device)| in_segment(device) && device.address.bus == bridge.bus)
+        .flat_map(|(position, device)| {
+            device
+                .bars()
+                .into_iter()
+                .filter(|bar| !bar.is_assigned())
+                .map(move |bar| (position, bar))
+        })
+        .collect::<Vec<_>>();
+    // the biggest first, so the smaller ones fill the gaps their alignment leaves
+    unassigned.sort_by_key(|(_, bar)| core::cmp::Reverse(bar.size));
+
+    for (position, bar) in unassigned {
+        let device = &mut devices[position];
+        let Some(address) = allocator.allocate(&bar) else {
+            warn!(
+                "No room for BAR {} of {} ({:?}, {:#x} bytes)",
+                bar.index, device.address, bar.kind, bar.size
+            );
+            continue;
+        };
+
+        config_write_u32(device.address, bar.offset(), address as u32);
+        if bar.kind == BarKind::Memory64 {
+            config_write_u32(device.address, bar.offset() + 4, (address >> 32) as u32);
+        }
+        device.decoded_bars[bar.index as usize] = Some(Bar { address, ..bar });
+        info!(
+            "Assigned BAR {} of {} to {:#x}..{:#x}",
+            bar.index,
+            device.address,
+            address,
+            address + bar.size
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::find_by_id;
+
+    // qemu's standard VGA, which q35 puts at 00:01.0
+    const VGA_ID: (u16, u16) = (0x1234, 0x1111);
+    const VGA_FRAMEBUFFER_SIZE: u64 = 16 << 20;
+
+    #[test_case]
+    fn vga_framebuffer_bar() {
+        let vga = find_by_id(VGA_ID.0, VGA_ID.1)
+            .next()
+            .expect("No standard VGA");
+        let bar = vga.bar(0).expect("VGA has no BAR 0");
+        assert_eq!(bar.kind, BarKind::Memory32);
+        assert!(bar.prefetchable);
+        assert!(bar.is_assigned());
+        assert_eq!(bar.size, VGA_FRAMEBUFFER_SIZE);
+        assert!(bar.address.is_multiple_of(bar.size));
+
+        // sizing it again finds the same BAR and leaves decoding the way it was
+        let command = config_read_u16(vga.address, REG_COMMAND);
+        let resized = vga.size_bar(0).unwrap();
+        assert_eq!((resized.address, resized.size), (bar.address, bar.size));
+        assert_eq!(config_read_u16(vga.address, REG_COMMAND), command);
+    }
+
+    fn unassigned_bar(kind: BarKind, size: u64) -> Bar {
+        Bar {
+            index: 0,
+            kind,
+            address: 0,
+            size,
+            prefetchable: false,
+        }
+    }
+
+    #[test_case]
+    fn allocator_aligns_and_skips_used() {
+        let mut allocator = Allocator {
+            windows: alloc::vec![
+                Window {
+                    kind: WindowKind::Memory,
+                    range: 0x8000_0000..0x9000_0000,
+                },
+                Window {
+                    kind: WindowKind::Memory,
+                    range: 0x10_0000_0000..0x10_1000_0000,
+                },
+            ],
+            used: alloc::vec![0x8000_0000..0x8000_1000, 0x8010_0000..0x8020_0000],
+        };
+
+        // the first aligned slots overlap the used ranges
+        let mib = allocator.allocate(&unassigned_bar(BarKind::Memory32, 1 << 20));
+        assert_eq!(mib, Some(0x8020_0000));
+        // smaller BARs fill the gaps
+        let page = allocator.allocate(&unassigned_bar(BarKind::Memory32, 0x1000));
+        assert_eq!(page, Some(0x8000_1000));
+        // only 64 bit BARs can go above 4 GiB
+        let big = unassigned_bar(BarKind::Memory32, 0x1000_0000);
+        assert_eq!(allocator.allocate(&big), None);
+        let big = unassigned_bar(BarKind::Memory64, 0x1000_0000);
+        assert_eq!(allocator.allocate(&big), Some(0x10_0000_0000));
+        // there is no I/O window
+        assert_eq!(allocator.allocate(&unassigned_bar(BarKind::Io, 0x10)), None);
+    }
+}
@@ -0,0 +1,134 @@
+use super::{HeaderType, PciDevice, config_read_u8, config_read_u16, config_read_u32};
+
+const REG_STATUS: u16 = 0x06;
+const REG_CAPABILITIES_POINTER: u16 = 0x34;
+const REG_CARDBUS_CAPABILITIES_POINTER: u16 = 0x14;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+
+const EXTENDED_CAPABILITIES_START: u16 = 0x100;
+
+// there's only room for this many in configuration space, anything more means a loop
+const MAX_CAPABILITIES: usize = 48;
+const MAX_EXTENDED_CAPABILITIES: usize = (4096 - 256) / 4;
+
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_MSI_X: u8 = 0x11;
+
+pub const EXT_CAP_ADVANCED_ERROR_REPORTING: u16 = 0x0001;
+pub const EXT_CAP_SERIAL_NUMBER: u16 = 0x0003;
+pub const EXT_CAP_SR_IOV: u16 = 0x0010;
+
+/// An entry in the standard capability list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where the capability starts in configuration space.
+    pub offset: u16,
+}
+
+/// An entry in the PCI Express extended capability list, which starts at 0x100.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+pub struct Capabilities {
+    device: PciDevice,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // the bottom two bits are reserved, and pointers into the header are invalid
+        if self.next < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let offset = self.next;
+        let header = config_read_u16(self.device.address, offset);
+        self.next = (header >> 8) & 0xFC;
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+pub struct ExtendedCapabilities {
+    device: PciDevice,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for ExtendedCapabilities {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<ExtendedCapability> {
+        if self.next < EXTENDED_CAPABILITIES_START || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let offset = self.next;
+        let header = config_read_u32(self.device.address, offset);
+        // conventional PCI devices (and legacy configuration access) read all ones or zeroes
+        if header == 0 || header == u32::MAX {
+            return None;
+        }
+        self.next = (header >> 20) as u16 & 0xFFC;
+        Some(ExtendedCapability {
+            id: header as u16,
+            version: (header >> 16) as u8 & 0xF,
+            offset,
+        })
+    }
+}
+
+impl PciDevice {
+    /// Walks the standard capability list.
+    pub fn capabilities(&self) -> Capabilities {
+        let has_list = config_read_u16(self.address, REG_STATUS) & STATUS_CAPABILITIES_LIST != 0;
+        let pointer = match self.header_type {
+            HeaderType::Endpoint | HeaderType::PciBridge => REG_CAPABILITIES_POINTER,
+            HeaderType::CardBusBridge => REG_CARDBUS_CAPABILITIES_POINTER,
+            HeaderType::Unknown(_) => 0,
+        };
+
+        Capabilities {
+            device: *self,
+            next: if has_list && pointer != 0 {
+                config_read_u8(self.address, pointer) as u16 & 0xFC
+            } else {
+                0
+            },
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// Walks the extended capability list, which is only reachable through ECAM.
+    pub fn extended_capabilities(&self) -> ExtendedCapabilities {
+        ExtendedCapabilities {
+            device: *self,
+            next: EXTENDED_CAPABILITIES_START,
+            remaining: MAX_EXTENDED_CAPABILITIES,
+        }
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|capability| capability.id == id)
+    }
+
+    pub fn find_extended_capability(&self, id: u16) -> Option<ExtendedCapability> {
+        self.extended_capabilities()
+            .find(|capability| capability.id == id)
+    }
+}
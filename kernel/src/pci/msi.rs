@@ -0,0 +1,324 @@
+use alloc::vec::Vec;
+
+use log::debug;
+
+use super::bar::{BarError, BarRegion, Mmio};
+use super::capability::{CAP_MSI, CAP_MSI_X};
+use super::{PciDevice, config_read_u16, config_read_u32, config_write_u16, config_write_u32};
+use crate::interrupts::controller::{self, InterruptController};
+use crate::interrupts::irq;
+
+const REG_COMMAND: u16 = 0x04;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS_LOW: u16 = 0x04;
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASK: u16 = 1 << 8;
+
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_BIR_MASK: u32 = 0b111;
+
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_ADDRESS_LOW: u64 = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: u64 = 0x4;
+const MSIX_ENTRY_DATA: u64 = 0x8;
+const MSIX_ENTRY_VECTOR_CONTROL: u64 = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+// messages are writes to the Local APIC's MMIO range
+const MESSAGE_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The device doesn't have the capability.
+    NotSupported,
+    /// Messages go to Local APICs, so they can't be used with the legacy PICs.
+    NoApic,
+    /// The destination doesn't fit into the 8 bit ID a message address has room for.
+    ApicIdTooLarge(u32),
+    NoFreeVector,
+    NoSuchEntry(u16),
+    /// The BAR holding the MSI-X table couldn't be mapped.
+    TableBar(BarError),
+}
+
+/// The message address that targets the Local APIC `apic_id`. The data is just the vector, for
+/// fixed delivery and edge triggering.
+fn message_address(apic_id: u32) -> Result<u32, MsiError> {
+    if controller::current() != InterruptController::Apic {
+        return Err(MsiError::NoApic);
+    }
+    if apic_id > 0xFF {
+        return Err(MsiError::ApicIdTooLarge(apic_id));
+    }
+    Ok(MESSAGE_ADDRESS_BASE | apic_id << 12)
+}
+
+/// Turns off the legacy INTx pin, which the device mustn't use alongside messages, and lets it
+/// write them.
+fn prepare_device(device: &PciDevice) {
+    let command = config_read_u16(device.address, REG_COMMAND);
+    config_write_u16(
+        device.address,
+        REG_COMMAND,
+        command | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE,
+    );
+}
+
+/// A device's MSI capability, only a single message is used.
+pub struct Msi {
+    device: PciDevice,
+    offset: u16,
+    control: u16,
+    /// The vector [`enable`](Self::enable) allocated, given back by [`disable`](Self::disable).
+    vector: Option<u8>,
+}
+
+impl Msi {
+    pub fn is_64bit(&self) -> bool {
+        self.control & MSI_CONTROL_64BIT != 0
+    }
+
+    pub fn can_mask(&self) -> bool {
+        self.control & MSI_CONTROL_PER_VECTOR_MASK != 0
+    }
+
+    fn data_offset(&self) -> u16 {
+        self.offset + if self.is_64bit() { 0x0C } else { 0x08 }
+    }
+
+    fn mask_offset(&self) -> u16 {
+        self.data_offset() + 4
+    }
+
+    /// Allocates a vector, points the message at the Local APIC `apic_id` and enables MSI.
+    ///
+    /// Returns the vector, handlers for it are installed with [`irq::request`]. Enabling it again
+    /// gives back the previous vector.
+    pub fn enable(&mut self, apic_id: u32) -> Result<u8, MsiError> {
+        let address = message_address(apic_id)?;
+        let vector = irq::allocate_vector().ok_or(MsiError::NoFreeVector)?;
+        if let Some(previous) = self.vector.replace(vector) {
+            irq::release_vector(previous);
+        }
+
+        let address_reg = self.offset + MSI_ADDRESS_LOW;
+        config_write_u32(self.device.address, address_reg, address);
+        if self.is_64bit() {
+            config_write_u32(self.device.address, address_reg + 4, 0);
+        }
+        // the data register is 16 bits wide
+        config_write_u16(self.device.address, self.data_offset(), vector as u16);
+
+        prepare_device(&self.device);
+        self.control = (self.control & !MSI_CONTROL_MULTIPLE_ENABLE) | MSI_CONTROL_ENABLE;
+        config_write_u16(self.device.address, self.offset + MSI_CONTROL, self.control);
+        if self.can_mask() {
+            self.unmask();
+        }
+
+        debug!(
+            "MSI of {} on vector {:#04x}, APIC {}",
+            self.device.address, vector, apic_id
+        );
+        Ok(vector)
+    }
+
+    /// Turns MSI off and gives back the vector, its handlers have to be freed separately.
+    pub fn disable(&mut self) {
+        self.control &= !MSI_CONTROL_ENABLE;
+        config_write_u16(self.device.address, self.offset + MSI_CONTROL, self.control);
+        if let Some(vector) = self.vector.take() {
+            irq::release_vector(vector);
+        }
+    }
+
+    /// Does nothing unless the device supports [per vector masking](Self::can_mask).
+    pub fn mask(&self) {
+        if self.can_mask() {
+            let mask = config_read_u32(self.device.address, self.mask_offset());
+            config_write_u32(self.device.address, self.mask_offset(), mask | 1);
+        }
+    }
+
+    pub fn unmask(&self) {
+        if self.can_mask() {
+            let mask = config_read_u32(self.device.address, self.mask_offset());
+            config_write_u32(self.device.address, self.mask_offset(), mask & !1);
+        }
+    }
+}
+
+/// A device's MSI-X capability and its mapped vector table.
+pub struct MsiX {
+    device: PciDevice,
+    offset: u16,
+    table: Mmio,
+    table_offset: u64,
+    entries: u16,
+    /// The vectors [`allocate`](Self::allocate) handed out, by entry.
+    vectors: Vec<(u16, u8)>,
+}
+
+impl MsiX {
+    /// How many entries the vector table has.
+    pub fn entries(&self) -> u16 {
+        self.entries
+    }
+
+    fn entry_offset(&self, entry: u16) -> Result<u64, MsiError> {
+        if entry >= self.entries {
+            return Err(MsiError::NoSuchEntry(entry));
+        }
+        Ok(self.table_offset + entry as u64 * MSIX_ENTRY_SIZE)
+    }
+
+    fn set_control(&self, set: u16, clear: u16) {
+        let control = config_read_u16(self.device.address, self.offset + MSIX_CONTROL);
+        config_write_u16(
+            self.device.address,
+            self.offset + MSIX_CONTROL,
+            (control | set) & !clear,
+        );
+    }
+
+    /// Enables MSI-X with every entry masked, they're unmasked by [`allocate`](Self::allocate).
+    pub fn enable(&mut self) {
+        for entry in 0..self.entries {
+            let _ = self.mask(entry);
+        }
+        prepare_device(&self.device);
+        self.set_control(MSIX_CONTROL_ENABLE, MSIX_CONTROL_FUNCTION_MASK);
+    }
+
+    /// Turns MSI-X off and gives back every allocated vector, their handlers have to be freed
+    /// separately.
+    pub fn disable(&mut self) {
+        self.set_control(0, MSIX_CONTROL_ENABLE);
+        for (_, vector) in self.vectors.drain(..) {
+            irq::release_vector(vector);
+        }
+    }
+
+    /// Allocates a vector, points `entry` at the Local APIC `apic_id` with it and unmasks the
+    /// entry.
+    ///
+    /// Returns the vector, handlers for it are installed with [`irq::request`]. Allocating an
+    /// entry again gives back its previous vector.
+    pub fn allocate(&mut self, entry: u16, apic_id: u32) -> Result<u8, MsiError> {
+        let offset = self.entry_offset(entry)?;
+        let address = message_address(apic_id)?;
+        let vector = irq::allocate_vector().ok_or(MsiError::NoFreeVector)?;
+
+        // masked while it's reprogrammed, so no half written message is sent
+        self.mask(entry)?;
+        self.table.write(offset + MSIX_ENTRY_ADDRESS_LOW, address);
+        self.table.write(offset + MSIX_ENTRY_ADDRESS_HIGH, 0u32);
+        self.table.write(offset + MSIX_ENTRY_DATA, vector as u32);
+        self.unmask(entry)?;
+        match self.vectors.iter_mut().find(|(used, _)| *used == entry) {
+            Some((_, previous)) => irq::release_vector(core::mem::replace(previous, vector)),
+            None => self.vectors.push((entry, vector)),
+        }
+
+        debug!(
+            "MSI-X entry {} of {} on vector {:#04x}, APIC {}",
+            entry, self.device.address, vector, apic_id
+        );
+        Ok(vector)
+    }
+
+    pub fn mask(&self, entry: u16) -> Result<(), MsiError> {
+        let control = self.entry_offset(entry)? + MSIX_ENTRY_VECTOR_CONTROL;
+        let value = self.table.read::<u32>(control);
+        self.table.write(control, value | MSIX_ENTRY_MASKED);
+        Ok(())
+    }
+
+    pub fn unmask(&self, entry: u16) -> Result<(), MsiError> {
+        let control = self.entry_offset(entry)? + MSIX_ENTRY_VECTOR_CONTROL;
+        let value = self.table.read::<u32>(control);
+        self.table.write(control, value & !MSIX_ENTRY_MASKED);
+        Ok(())
+    }
+}
+
+impl PciDevice {
+    pub fn msi(&self) -> Result<Msi, MsiError> {
+        let capability = self
+            .find_capability(CAP_MSI)
+            .ok_or(MsiError::NotSupported)?;
+        Ok(Msi {
+            device: *self,
+            offset: capability.offset,
+            control: config_read_u16(self.address, capability.offset + MSI_CONTROL),
+            vector: None,
+        })
+    }
+
+    /// Finds the MSI-X capability and maps the BAR with its vector table.
+    pub fn msix(&self) -> Result<MsiX, MsiError> {
+        let capability = self
+            .find_capability(CAP_MSI_X)
+            .ok_or(MsiError::NotSupported)?;
+        let control = config_read_u16(self.address, capability.offset + MSIX_CONTROL);
+        let table = config_read_u32(self.address, capability.offset + MSIX_TABLE);
+
+        let bar = (table & MSIX_BIR_MASK) as u8;
+        let BarRegion::Memory(mmio) = self.map_bar(bar).map_err(MsiError::TableBar)? else {
+            return Err(MsiError::TableBar(BarError::NoSuchBar(bar)));
+        };
+        Ok(MsiX {
+            device: *self,
+            offset: capability.offset,
+            table: mmio,
+            table_offset: (table & !MSIX_BIR_MASK) as u64,
+            entries: (control & MSIX_CONTROL_TABLE_SIZE) + 1,
+            vectors: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acpi::apic;
+    use crate::pci::{PciAddress, devices};
+
+    // q35's ICH9 AHCI controller, which supports MSI
+    const AHCI: PciAddress = PciAddress::new(0, 0, 0x1F, 2);
+
+    #[test_case]
+    fn ahci_msi() {
+        let ahci = devices()
+            .iter()
+            .find(|device| device.address == AHCI)
+            .expect("No AHCI controller at 00:1f.2");
+        let command = config_read_u16(AHCI, REG_COMMAND);
+        let mut msi = ahci.msi().expect("AHCI has no MSI capability");
+        let control = msi.offset + MSI_CONTROL;
+
+        let vector = msi.enable(apic::id()).unwrap();
+        assert_eq!(
+            config_read_u32(AHCI, msi.offset + MSI_ADDRESS_LOW),
+            MESSAGE_ADDRESS_BASE | apic::id() << 12
+        );
+        assert_eq!(config_read_u16(AHCI, msi.data_offset()), vector as u16);
+        assert_ne!(config_read_u16(AHCI, control) & MSI_CONTROL_ENABLE, 0);
+
+        msi.disable();
+        assert_eq!(config_read_u16(AHCI, control) & MSI_CONTROL_ENABLE, 0);
+        // the vector was given back, so it's the first free one again
+        assert_eq!(irq::allocate_vector(), Some(vector));
+        irq::release_vector(vector);
+        config_write_u16(AHCI, REG_COMMAND, command);
+    }
+}
@@ -0,0 +1,123 @@
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::cell::Cell;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::tss::TaskStateSegment;
+
+use crate::memory::ERROR_ADDRESS;
+use crate::smp::MAX_CPUS;
+
+/// State that belongs to a single CPU, reached through `GS_BASE`.
+///
+/// Only the CPU owning it ever touches it, so plain [`Cell`]s are enough as long as interrupt
+/// handlers leave every field the way they found it (or only count things). Other CPUs only
+/// touch the atomic fields, through [`get`].
+#[repr(C)]
+pub struct PerCpu {
+    // points back at this struct, so `gs:[0]` gives its address
+    this: *const PerCpu,
+    /// The CPU's number, 0 being the BSP.
+    pub cpu: usize,
+    pub apic_id: u32,
+    /// Where the Local APIC's registers are mapped, set by [`crate::acpi::apic::enable`]. Unused
+    /// in x2APIC mode.
+    pub apic_base: Cell<u64>,
+    /// The task running on this CPU, 0 until there is a scheduler.
+    pub current_task: Cell<usize>,
+    /// How many interrupt handlers are running on this CPU, more than 1 when they nest.
+    pub irq_depth: Cell<u32>,
+    pub tss: Cell<Option<&'static TaskStateSegment>>,
+    /// Set by whoever sends this CPU an NMI IPI, so the NMI handler knows it isn't a hardware
+    /// error.
+    pub nmi_pending: AtomicBool,
+    pub stats: CpuStats,
+}
+
+/// Counters for a single CPU.
+#[derive(Default)]
+pub struct CpuStats {
+    pub interrupts: Cell<u64>,
+    pub unhandled_interrupts: Cell<u64>,
+    /// Local APIC timer interrupts.
+    pub timer_ticks: Cell<u64>,
+}
+
+/// Reads the `field` of the current CPU's [`PerCpu`], e.g. `percpu!(cpu)` or
+/// `percpu!(irq_depth).get()`.
+#[macro_export]
+macro_rules! percpu {
+    ($($field:ident).+) => {
+        &$crate::percpu::current().$($field).+
+    };
+}
+
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(null_mut()) }; MAX_CPUS];
+
+/// Sets up the per-CPU data of the current CPU as CPU number `cpu`.
+///
+/// `KERNEL_GS_BASE` is cleared, so a `swapgs` on the way in from user mode will find the
+/// per-CPU data there once user mode exists. The heap has to be initialized first.
+pub fn init(cpu: usize) {
+    let percpu = Box::leak(Box::new(PerCpu {
+        this: core::ptr::null(),
+        cpu,
+        apic_id: apic_id(),
+        apic_base: Cell::new(ERROR_ADDRESS),
+        current_task: Cell::new(0),
+        irq_depth: Cell::new(0),
+        tss: Cell::new(None),
+        nmi_pending: AtomicBool::new(false),
+        stats: CpuStats::default(),
+    }));
+    percpu.this = percpu;
+    CPUS[cpu].store(percpu, Ordering::Release);
+
+    GsBase::write(VirtAddr::from_ptr(percpu.this));
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// The per-CPU data of the CPU this runs on, [`init`] has to be called on it first.
+pub fn current() -> &'static PerCpu {
+    let percpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) percpu, options(nostack, preserves_flags, readonly));
+        &*percpu
+    }
+}
+
+/// The per-CPU data of CPU number `cpu`, once it has been set up. Only its atomic fields may be
+/// used, unless `cpu` is the current CPU.
+pub fn get(cpu: usize) -> Option<&'static PerCpu> {
+    let percpu = CPUS.get(cpu)?.load(Ordering::Acquire);
+    unsafe { percpu.as_ref() }
+}
+
+/// Whether an interrupt handler is running on the current CPU.
+pub fn in_interrupt() -> bool {
+    current().irq_depth.get() > 0
+}
+
+/// The initial APIC ID from CPUID, which works before the Local APIC is set up.
+fn apic_id() -> u32 {
+    // leaf 0xB has the full 32 bit x2APIC ID, leaf 1 only the low 8 bits
+    if __cpuid(0).eax >= 0xB && __cpuid(0xB).ebx != 0 {
+        __cpuid(0xB).edx
+    } else {
+        __cpuid(1).ebx >> 24
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test_case]
+    fn bsp_percpu() {
+        assert_eq!(*percpu!(cpu), 0);
+        assert_eq!(percpu!(irq_depth).get(), 0);
+        assert!(core::ptr::eq(super::current(), super::current().this));
+    }
+}
@@ -0,0 +1,208 @@
+pub mod ipi;
+mod trampoline;
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ::acpi::platform::ProcessorState;
+use arrayvec::ArrayVec;
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::acpi::{self, apic};
+use crate::interrupts::controller::InterruptController;
+use crate::interrupts::irq::{self, IrqReturn};
+use crate::interrupts::pit;
+use crate::memory::{self, stack};
+use crate::{gdt, interrupts, percpu};
+use ipi::{Delivery, Destination, ICR_INIT, ICR_LEVEL_ASSERT, ICR_STARTUP};
+use trampoline::Trampoline;
+
+pub const MAX_CPUS: usize = 64;
+/// Wakes idle APs up to check for work from [`run_on_all`].
+pub const WAKEUP_VECTOR: u8 = 0xFC;
+const AP_STACK_SIZE: u64 = 64 * 1024;
+
+/// How long to wait for an AP to report in after the startup IPIs.
+const AP_TIMEOUT_MS: u64 = 100;
+
+/// Local APIC IDs of the CPUs that are online, indexed by CPU number. The BSP is CPU 0.
+static mut APIC_IDS: ArrayVec<u32, MAX_CPUS> = ArrayVec::new_const();
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+// `run_on_all` publishes work by bumping `GENERATION` and waits for `PENDING` to reach 0
+static mut WORK: Option<&'static (dyn Fn(usize) + Sync)> = None;
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static RUN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Starts every AP the MADT lists, one after another.
+///
+/// Each AP gets its own stack, GDT and TSS, loads the IDT and enables its Local APIC before
+/// halting until there is work from [`run_on_all`].
+pub fn init() {
+    unsafe {
+        APIC_IDS.push(apic::id());
+    }
+
+    if interrupts::controller::current() != InterruptController::Apic {
+        warn!("SMP needs the APIC interrupt controller, only the BSP is used");
+        return;
+    }
+    memory::tlb::init();
+    // the interrupt itself is all the APs need, they look for work once they are awake
+    irq::request(
+        WAKEUP_VECTOR,
+        "wakeup",
+        false,
+        |_| IrqReturn::Handled,
+        core::ptr::null_mut(),
+    )
+    .expect("Wakeup vector already in use")
+    .leak();
+
+    let Some(processors) = acpi::init::processor_info() else {
+        warn!("The MADT doesn't list any processors, only the BSP is used");
+        return;
+    };
+    if !trampoline::page_tables_reachable() {
+        warn!("The page tables are above 4 GiB where APs can't load them, only the BSP is used");
+        return;
+    }
+    let Some(trampoline) = Trampoline::install() else {
+        warn!("No free page below 1 MiB for the AP trampoline, only the BSP is used");
+        return;
+    };
+
+    for processor in processors.application_processors.iter() {
+        if processor.state != ProcessorState::WaitingForSipi {
+            continue;
+        }
+        if cpu_count() == MAX_CPUS {
+            warn!("Ignoring CPUs beyond the first {}", MAX_CPUS);
+            break;
+        }
+
+        if !start_ap(&trampoline, processor.local_apic_id) {
+            warn!("CPU with APIC ID {} didn't start", processor.local_apic_id);
+        }
+    }
+
+    info!("{} CPUs online", cpu_count());
+}
+
+fn start_ap(trampoline: &Trampoline, apic_id: u32) -> bool {
+    let cpu = cpu_count();
+    let online = ONLINE.load(Ordering::Acquire);
+    let stack = stack::allocate("AP", AP_STACK_SIZE);
+    trampoline.prepare(stack.as_u64(), ap_main, cpu);
+    unsafe {
+        APIC_IDS.push(apic_id);
+    }
+
+    // INIT, then up to two startup IPIs as in the Intel MP specification
+    apic::send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+    pit::wait_polled(10_000);
+    for _ in 0..2 {
+        apic::send_ipi(
+            apic_id,
+            ICR_STARTUP | ICR_LEVEL_ASSERT | trampoline.vector() as u32,
+        );
+        pit::wait_polled(200);
+        if ONLINE.load(Ordering::Acquire) > online {
+            break;
+        }
+    }
+
+    for _ in 0..AP_TIMEOUT_MS {
+        if ONLINE.load(Ordering::Acquire) > online {
+            return true;
+        }
+        pit::wait_polled(1000);
+    }
+
+    unsafe {
+        APIC_IDS.pop();
+    }
+    false
+}
+
+/// Where the trampoline leaves an AP, on the stack the BSP allocated for it.
+extern "C" fn ap_main(cpu: u64) -> ! {
+    use x86_64::instructions::interrupts::{disable, enable, enable_and_hlt};
+
+    let cpu = cpu as usize;
+    percpu::init(cpu);
+    gdt::init();
+    interrupts::init_idt();
+    apic::enable();
+    apic::timer::init();
+    info!("CPU {} online (APIC ID {})", cpu, apic::id());
+
+    // the BSP starts the next AP once this one is done with the trampoline and its setup
+    ONLINE.fetch_add(1, Ordering::Release);
+
+    let mut seen = GENERATION.load(Ordering::Acquire);
+    loop {
+        // interrupts are off between the check and the `hlt`, so a wakeup IPI arriving right
+        // after the check still ends the `hlt` instead of being handled before it
+        disable();
+        let generation = GENERATION.load(Ordering::Acquire);
+        if generation == seen {
+            enable_and_hlt();
+            continue;
+        }
+        enable();
+
+        seen = generation;
+        if let Some(work) = unsafe { WORK } {
+            work(cpu);
+        }
+        PENDING.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// How many CPUs are online, including the BSP.
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// The Local APIC ID of CPU number `cpu`, if it is online.
+pub fn apic_id(cpu: usize) -> Option<u32> {
+    unsafe { APIC_IDS.get(cpu).copied() }
+}
+
+/// The number of the current CPU, 0 being the BSP.
+pub fn current_cpu() -> usize {
+    *percpu!(cpu)
+}
+
+/// Runs `f` on every online CPU, passing it the CPU's number, and returns once all are done.
+///
+/// The APs only pick up work while idle, so this must not be called from `f` itself.
+pub fn run_on_all(f: impl Fn(usize) + Sync) {
+    let _guard = RUN_LOCK.lock();
+    let work: &(dyn Fn(usize) + Sync) = &f;
+
+    unsafe {
+        // `f` outlives the APs' use of it, since this waits for all of them to finish
+        WORK = Some(core::mem::transmute::<
+            &(dyn Fn(usize) + Sync),
+            &'static (dyn Fn(usize) + Sync),
+        >(work));
+        PENDING.store(ONLINE.load(Ordering::Acquire) - 1, Ordering::Release);
+        GENERATION.fetch_add(1, Ordering::AcqRel);
+    }
+    if cpu_count() > 1 {
+        ipi::send(Destination::AllButSelf, Delivery::Fixed(WAKEUP_VECTOR));
+    }
+
+    work(current_cpu());
+    while PENDING.load(Ordering::Acquire) != 0 {
+        spin_loop();
+    }
+
+    unsafe {
+        WORK = None;
+    }
+}
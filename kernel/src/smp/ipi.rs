@@ -0,0 +1,88 @@
+use core::sync::atomic::Ordering;
+
+use crate::acpi::apic;
+use crate::percpu;
+
+const ICR_FIXED: u32 = 0b000 << 8;
+const ICR_NMI: u32 = 0b100 << 8;
+pub(super) const ICR_INIT: u32 = 0b101 << 8;
+pub(super) const ICR_STARTUP: u32 = 0b110 << 8;
+pub(super) const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+const ICR_SHORTHAND_SELF: u32 = 0b01 << 18;
+const ICR_SHORTHAND_ALL: u32 = 0b10 << 18;
+const ICR_SHORTHAND_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+/// Who an IPI is sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// A single CPU, by its number (see [`super::current_cpu`]).
+    Cpu(usize),
+    /// The sending CPU.
+    Current,
+    /// Every CPU, including the sending one.
+    All,
+    /// Every CPU except the sending one.
+    AllButSelf,
+}
+
+/// What an IPI does when it arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Raises the interrupt with this vector, handlers are installed with
+    /// [`crate::interrupts::irq`].
+    Fixed(u8),
+    /// Raises a non-maskable interrupt, which ignores the vector and the interrupt flag. The
+    /// receivers are told to expect it, so their NMI handler returns instead of panicking.
+    Nmi,
+}
+
+/// Sends an inter-processor interrupt through the Local APIC.
+///
+/// Only works once the Local APIC is up, i.e. with the APIC interrupt controller.
+pub fn send(destination: Destination, delivery: Delivery) {
+    let mut command = ICR_LEVEL_ASSERT
+        | match delivery {
+            Delivery::Fixed(vector) => ICR_FIXED | vector as u32,
+            Delivery::Nmi => ICR_NMI,
+        };
+    if delivery == Delivery::Nmi {
+        expect_nmi(destination);
+    }
+
+    let apic_id = match destination {
+        Destination::Cpu(cpu) => super::apic_id(cpu).expect("IPI to a CPU that isn't online"),
+        Destination::Current => {
+            command |= ICR_SHORTHAND_SELF;
+            0
+        }
+        Destination::All => {
+            command |= ICR_SHORTHAND_ALL;
+            0
+        }
+        Destination::AllButSelf => {
+            command |= ICR_SHORTHAND_ALL_BUT_SELF;
+            0
+        }
+    };
+
+    apic::send_ipi(apic_id, command);
+}
+
+/// Sets [`percpu::PerCpu::nmi_pending`] on every CPU an NMI is about to be sent to.
+fn expect_nmi(destination: Destination) {
+    let current = super::current_cpu();
+    let cpus = match destination {
+        Destination::Cpu(cpu) => cpu..cpu + 1,
+        Destination::Current => current..current + 1,
+        Destination::All | Destination::AllButSelf => 0..super::cpu_count(),
+    };
+    for cpu in cpus {
+        if destination == Destination::AllButSelf && cpu == current {
+            continue;
+        }
+        if let Some(percpu) = percpu::get(cpu) {
+            percpu.nmi_pending.store(true, Ordering::Release);
+        }
+    }
+}
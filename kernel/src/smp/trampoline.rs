@@ -0,0 +1,168 @@
+use core::arch::global_asm;
+
+use log::debug;
+
+use crate::logger::LoggedAddress;
+use crate::memory;
+
+// Real mode entry point for the APs, copied to a page below 1 MiB. It enables long mode with the
+// BSP's page tables and a throwaway GDT, switches to the stack in `Data` and calls `Data::entry`.
+// The startup IPI sets CS to the page, so the 16 bit code addresses everything relative to it.
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".balign 16",
+    ".code16",
+    "AP_TRAMPOLINE_START:",
+    "    jmp ap_real_mode",
+    ".balign 8",
+    // `Data`, filled in by the BSP
+    "ap_cr3: .quad 0",
+    "ap_stack: .quad 0",
+    "ap_entry: .quad 0",
+    "ap_cpu: .quad 0",
+    "ap_far_jump: .long 0",
+    "    .word 0x08",
+    "ap_gdt_ptr: .word 23",
+    "    .long 0",
+    ".balign 8",
+    "AP_TRAMPOLINE_GDT:",
+    "    .quad 0",
+    "    .quad 0x00AF9A000000FFFF", // 64 bit code
+    "    .quad 0x00CF92000000FFFF", // data
+    ".set ap_gdt_ptr_offset, ap_gdt_ptr - AP_TRAMPOLINE_START",
+    ".set ap_cr3_offset, ap_cr3 - AP_TRAMPOLINE_START",
+    ".set ap_far_jump_offset, ap_far_jump - AP_TRAMPOLINE_START",
+    "ap_real_mode:",
+    "    cli",
+    "    cld",
+    "    movw %cs, %ax",
+    "    movw %ax, %ds",
+    "    lgdtl ap_gdt_ptr_offset",
+    // PAE
+    "    movl %cr4, %eax",
+    "    orl $(1 << 5), %eax",
+    "    movl %eax, %cr4",
+    "    movl ap_cr3_offset, %eax",
+    "    movl %eax, %cr3",
+    // EFER: long mode and no-execute
+    "    movl $0xC0000080, %ecx",
+    "    rdmsr",
+    "    orl $((1 << 8) | (1 << 11)), %eax",
+    "    wrmsr",
+    // paging, write protect and protected mode all at once
+    "    movl %cr0, %eax",
+    "    orl $0x80010001, %eax",
+    "    movl %eax, %cr0",
+    "    ljmpl *ap_far_jump_offset",
+    ".code64",
+    "AP_TRAMPOLINE_LONG_MODE:",
+    "    movw $0x10, %ax",
+    "    movw %ax, %ds",
+    "    movw %ax, %es",
+    "    movw %ax, %ss",
+    "    movq ap_stack(%rip), %rsp",
+    "    movq ap_cpu(%rip), %rdi",
+    "    movq ap_entry(%rip), %rax",
+    "    callq *%rax",
+    "    ud2",
+    "AP_TRAMPOLINE_END:",
+    ".popsection",
+    options(att_syntax),
+);
+
+unsafe extern "C" {
+    static AP_TRAMPOLINE_START: u8;
+    static AP_TRAMPOLINE_GDT: u8;
+    static AP_TRAMPOLINE_LONG_MODE: u8;
+    static AP_TRAMPOLINE_END: u8;
+}
+
+/// Offset of [`Data`] in the trampoline.
+const DATA_OFFSET: u64 = 8;
+/// The trampoline has to fit in the page it starts from.
+const PAGE_SIZE: u64 = 4096;
+// The startup IPI can only point at a page between 0x1000 and the EBDA.
+const LOW_PAGES: core::ops::Range<u64> = 0x1000..0x9F000;
+
+/// What the BSP tells an AP before starting it.
+#[repr(C)]
+struct Data {
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+    far_jump: u32,
+    _far_jump_selector: u16,
+    _gdt_limit: u16,
+    gdt_base: u32,
+}
+
+fn page_tables() -> u64 {
+    x86_64::registers::control::Cr3::read()
+        .0
+        .start_address()
+        .as_u64()
+}
+
+/// Whether an AP can load the current page tables, which the trampoline does while still in
+/// real mode with a 32 bit CR3.
+pub(super) fn page_tables_reachable() -> bool {
+    page_tables() < 1 << 32
+}
+
+pub(super) struct Trampoline {
+    base: u64,
+}
+
+impl Trampoline {
+    /// Copies the trampoline to the first free page below 1 MiB.
+    ///
+    /// The frame allocator never hands those out, so nothing else uses the page.
+    pub(super) fn install() -> Option<Self> {
+        let base = LOW_PAGES.step_by(PAGE_SIZE as usize).find(|&page| {
+            memory::is_usable_ram(page) && memory::is_usable_ram(page + PAGE_SIZE - 1)
+        })?;
+        memory::map_identity(base..base + PAGE_SIZE);
+
+        unsafe {
+            let start = &raw const AP_TRAMPOLINE_START;
+            let len = (&raw const AP_TRAMPOLINE_END).offset_from(start) as usize;
+            assert!(
+                len as u64 <= PAGE_SIZE,
+                "AP trampoline doesn't fit in a page"
+            );
+            core::ptr::copy_nonoverlapping(start, base as *mut u8, len);
+
+            let data = &mut *((base + DATA_OFFSET) as *mut Data);
+            data.far_jump =
+                (base + (&raw const AP_TRAMPOLINE_LONG_MODE).offset_from(start) as u64) as u32;
+            data.gdt_base =
+                (base + (&raw const AP_TRAMPOLINE_GDT).offset_from(start) as u64) as u32;
+        }
+
+        debug!(
+            "Installed AP trampoline at {:?}",
+            LoggedAddress::Physical(base)
+        );
+        Some(Self { base })
+    }
+
+    /// The vector for the startup IPI, i.e. the page number of the trampoline.
+    pub(super) fn vector(&self) -> u8 {
+        (self.base >> 12) as u8
+    }
+
+    /// Sets up the trampoline for the next AP, which will call `entry(cpu)` on `stack`.
+    ///
+    /// The page tables have to be below 4 GiB, see [`page_tables_reachable`].
+    pub(super) fn prepare(&self, stack: u64, entry: extern "C" fn(u64) -> !, cpu: usize) {
+        unsafe {
+            let data = &mut *((self.base + DATA_OFFSET) as *mut Data);
+            data.cr3 = page_tables();
+            data.stack = stack;
+            data.entry = entry as usize as u64;
+            data.cpu = cpu as u64;
+        }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    }
+}
@@ -35,6 +35,7 @@ bitflags! {
         const x2APIC = 1 << 21;
         const MOVBE = 1 << 22;
         const POPCNT = 1 << 23;
+        const TSC_DEADLINE = 1 << 24;
         const XSAVE = 1 << 26;
         const OSXSAVE = 1 << 27;
 
@@ -0,0 +1,35 @@
+use core::panic::PanicInfo;
+
+use crate::debug_utils::{QemuExitCode, exit_qemu};
+
+/// A `#[test_case]` item that can be run by [`test_runner`].
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print!("{}...\t", core::any::type_name::<T>());
+        self();
+        println!("\x1b[0;92m[ok]\x1b[0m");
+    }
+}
+
+/// Runs every `#[test_case]` in the test binary and exits QEMU with [`QemuExitCode::Success`].
+///
+/// A failing test panics, which is expected to end up in [`test_panic_handler`].
+pub fn test_runner(tests: &[&dyn Testable]) {
+    println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Panic handler for test binaries, reports the failing test over `SERIAL` and exits QEMU with
+/// [`QemuExitCode::Failed`].
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    println!("\x1b[0;91m[failed]\x1b[0m");
+    println!("\x1b[0;91mError: {info}\x1b[0m");
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -0,0 +1,142 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::hint::spin_loop;
+
+use log::{info, warn};
+
+use crate::acpi::hpet;
+use crate::interrupts::pit;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+const CALIBRATION_MS: u64 = 10;
+// the PIT can't busy wait for longer than about 54 ms at a time
+const PIT_STALL_CHUNK_MICROS: u64 = 50_000;
+
+/// Where [`monotonic_nanos`] gets its time from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// [`init`] hasn't run yet, the clock reads 0.
+    None,
+    Tsc,
+    Hpet,
+}
+
+static mut SOURCE: ClockSource = ClockSource::None;
+static mut TSC_FREQUENCY: u64 = 0;
+
+/// Whether the TSC ticks at a constant rate regardless of P-, C- and T-states.
+pub fn has_invariant_tsc() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Calibrates the TSC and picks the clock source, so the HPET (if wanted) has to be initialized
+/// first.
+///
+/// An invariant TSC is the cheapest to read, so it's preferred. Otherwise the HPET is used, and
+/// without one the TSC is used anyway, although it may drift.
+pub fn init() {
+    unsafe {
+        TSC_FREQUENCY = calibrate_tsc();
+    }
+
+    let invariant = has_invariant_tsc();
+    let source = if invariant || !hpet::available() {
+        if !invariant {
+            warn!("Neither an invariant TSC nor an HPET, time may drift");
+        }
+        ClockSource::Tsc
+    } else {
+        ClockSource::Hpet
+    };
+
+    unsafe {
+        SOURCE = source;
+    }
+    info!("Clock source: {:?}", source);
+}
+
+/// Measures the TSC against the HPET, or the PIT without one. Everything else calibrates against
+/// the clock afterwards.
+fn calibrate_tsc() -> u64 {
+    let start = unsafe { _rdtsc() };
+    if hpet::available() {
+        let hpet_start = hpet::nanos();
+        while hpet::nanos() - hpet_start < CALIBRATION_MS * 1_000_000 {
+            spin_loop();
+        }
+    } else {
+        pit::wait_polled(CALIBRATION_MS * 1000);
+    }
+    let frequency = (unsafe { _rdtsc() } - start) * 1000 / CALIBRATION_MS;
+    info!("Calibrated TSC: {} Hz", frequency);
+    frequency
+}
+
+/// TSC ticks per second, 0 until [`init`] has run.
+pub fn tsc_frequency() -> u64 {
+    unsafe { TSC_FREQUENCY }
+}
+
+pub fn clock_source() -> ClockSource {
+    unsafe { SOURCE }
+}
+
+/// Nanoseconds since boot, never going backwards. Reads 0 until [`init`] has run.
+pub fn monotonic_nanos() -> u64 {
+    match clock_source() {
+        ClockSource::None => 0,
+        ClockSource::Tsc => {
+            // the TSC starts at 0 on reset, so this is close enough to the time since boot
+            (unsafe { _rdtsc() } as u128 * NANOS_PER_SEC / unsafe { TSC_FREQUENCY } as u128) as u64
+        }
+        ClockSource::Hpet => hpet::nanos(),
+    }
+}
+
+/// Busy waits for `micros` microseconds, which also works with interrupts disabled and before
+/// [`init`].
+pub fn stall(micros: u64) {
+    if clock_source() == ClockSource::None {
+        let mut remaining = micros;
+        while remaining > 0 {
+            let chunk = remaining.min(PIT_STALL_CHUNK_MICROS);
+            pit::wait_polled(chunk);
+            remaining -= chunk;
+        }
+        return;
+    }
+
+    let deadline = monotonic_nanos().saturating_add(micros.saturating_mul(1000));
+    while monotonic_nanos() < deadline {
+        spin_loop();
+    }
+}
+
+/// Waits for `millis` milliseconds.
+///
+/// There are no tasks to switch to yet, so for now this busy waits like [`stall`]. Once there is
+/// a scheduler it should block the current task instead.
+pub fn sleep(millis: u64) {
+    stall(millis.saturating_mul(1000));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn clock_is_monotonic() {
+        let mut last = monotonic_nanos();
+        for _ in 0..1000 {
+            let now = monotonic_nanos();
+            assert!(now >= last);
+            last = now;
+        }
+    }
+
+    #[test_case]
+    fn stall_waits() {
+        let start = monotonic_nanos();
+        stall(1000);
+        assert!(monotonic_nanos() - start >= 1_000_000);
+    }
+}
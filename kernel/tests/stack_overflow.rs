@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+use bootloader_api::{BootInfo, entry_point};
+use kernel::BOOTLOADER_CONFIG;
+use kernel::debug_utils::{QemuExitCode, exit_qemu};
+use kernel::{print, println};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+
+    print!("stack_overflow::stack_overflow...\t");
+    stack_overflow();
+
+    println!("\x1b[0;91m[failed]\x1b[0m");
+    println!("\x1b[0;91mError: execution continued after stack overflow\x1b[0m");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    // prevent tail recursion optimizations
+    core::hint::black_box(0);
+}
+
+/// Collects the start of a panic message.
+struct Prefix {
+    buf: [u8; 32],
+    len: usize,
+}
+
+impl Write for Prefix {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut prefix = Prefix {
+        buf: [0; 32],
+        len: 0,
+    };
+    let _ = write!(prefix, "{}", info.message());
+    if !prefix.buf[..prefix.len].starts_with(b"STACK OVERFLOW") {
+        kernel::testing::test_panic_handler(info)
+    }
+
+    println!("\x1b[0;92m[ok]\x1b[0m");
+    exit_qemu(QemuExitCode::Success);
+}
@@ -0,0 +1,223 @@
+use regex::Regex;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_UEFI: bool = true;
+const DEFAULT_MEMORY: &str = "8G";
+const DEFAULT_SMP: u32 = 1;
+const DEFAULT_CPU: &str = "qemu64";
+const DEFAULT_MACHINE: &str = "q35";
+const DEFAULT_GDB_SCRIPT: &str = "target/kernel.gdb";
+
+pub enum Mode {
+    /// Boot the kernel image that was built by build.rs.
+    Run,
+    /// Boot a kernel test binary, used as the cargo runner for the kernel target.
+    Test(PathBuf),
+    /// Boot the kernel with qemu's gdb stub enabled and the CPU halted until gdb connects.
+    Debug,
+}
+
+pub struct Options {
+    pub mode: Mode,
+    pub uefi: bool,
+    pub headless: bool,
+    pub timeout: Option<Duration>,
+    pub memory: String,
+    pub smp: u32,
+    pub cpu: String,
+    pub machine: String,
+    pub kvm: bool,
+    pub ovmf_code: Option<PathBuf>,
+    pub ovmf_vars: Option<PathBuf>,
+    /// Use a writable copy of the OVMF VARS that is kept between runs instead of `snapshot=on`.
+    pub persist_vars: bool,
+    /// Where `debug` writes the gdb script to.
+    pub gdb_script: PathBuf,
+    /// File the serial output is copied to.
+    pub log: Option<PathBuf>,
+    pub strip_ansi: bool,
+    /// The run passes as soon as a line of serial output matches this.
+    pub expect: Option<Regex>,
+    /// Passed to qemu verbatim after everything else.
+    pub qemu_args: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Run,
+            uefi: DEFAULT_UEFI,
+            headless: false,
+            timeout: None,
+            memory: DEFAULT_MEMORY.to_string(),
+            smp: DEFAULT_SMP,
+            cpu: DEFAULT_CPU.to_string(),
+            machine: DEFAULT_MACHINE.to_string(),
+            kvm: false,
+            ovmf_code: None,
+            ovmf_vars: None,
+            persist_vars: false,
+            gdb_script: PathBuf::from(DEFAULT_GDB_SCRIPT),
+            log: None,
+            strip_ansi: false,
+            expect: None,
+            qemu_args: Vec::new(),
+        }
+    }
+}
+
+pub fn usage(prog: &str) -> String {
+    format!(
+        "\
+Usage: {prog} [uefi|bios] [options] [-- <qemu args>...]
+       {prog} test <kernel-binary> [options] [-- <qemu args>...]
+       {prog} debug [uefi|bios] [options] [-- <qemu args>...]
+  uefi                 - boot using OVMF (UEFI)
+  bios                 - boot using legacy BIOS
+  test                 - build a BIOS image for a kernel test binary and run it headless
+  debug                - wait for gdb on localhost:1234 and write a gdb script for the kernel
+
+Options:
+  -m, --memory <size>  - guest memory, in qemu's -m syntax (default {DEFAULT_MEMORY})
+  --smp <count>        - number of guest CPUs (default {DEFAULT_SMP})
+  --cpu <model>        - qemu CPU model (default {DEFAULT_CPU}), e.g. qemu64,+x2apic
+  --machine <type>     - qemu machine type (default {DEFAULT_MACHINE})
+  --kvm                - enable KVM acceleration
+  --ovmf-code <path>   - OVMF CODE firmware (default: $OVMF_CODE, system OVMF, or download)
+  --ovmf-vars <path>   - OVMF VARS firmware (default: $OVMF_VARS, system OVMF, or download)
+  --persist-vars       - keep a writable copy of the OVMF VARS instead of discarding changes
+  --gdb-script <path>  - where `debug` writes the gdb script (default {DEFAULT_GDB_SCRIPT})
+  --log <path>         - also write the serial output to a file
+  --strip-ansi         - remove ANSI escape codes from the --log file
+  --expect <regex>     - pass and stop qemu once a serial output line matches
+  --qemu-arg <arg>     - pass a single extra argument to qemu, may be repeated
+  --headless           - don't open a display window (-display none)
+  --timeout <seconds>  - kill qemu after the given number of seconds
+  -h, --help           - print this message"
+    )
+}
+
+/// Parses the runner's arguments (without the program name).
+///
+/// Returns `Ok(None)` if help was requested.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            firmware if firmware.eq_ignore_ascii_case("uefi") => options.uefi = true,
+            firmware if firmware.eq_ignore_ascii_case("bios") => options.uefi = false,
+            "test" => {
+                let kernel = args
+                    .next()
+                    .ok_or("test expects the path of a kernel binary")?;
+                options.mode = Mode::Test(PathBuf::from(kernel));
+            }
+            "debug" => options.mode = Mode::Debug,
+            "-m" | "--memory" => options.memory = value(&arg, args.next())?,
+            "--smp" => options.smp = parse_value(&arg, args.next())?,
+            "--cpu" => options.cpu = value(&arg, args.next())?,
+            "--machine" => options.machine = value(&arg, args.next())?,
+            "--kvm" => options.kvm = true,
+            "--ovmf-code" => options.ovmf_code = Some(value(&arg, args.next())?.into()),
+            "--ovmf-vars" => options.ovmf_vars = Some(value(&arg, args.next())?.into()),
+            "--persist-vars" => options.persist_vars = true,
+            "--gdb-script" => options.gdb_script = value(&arg, args.next())?.into(),
+            "--log" => options.log = Some(value(&arg, args.next())?.into()),
+            "--strip-ansi" => options.strip_ansi = true,
+            "--expect" => {
+                let pattern = value(&arg, args.next())?;
+                let regex = Regex::new(&pattern)
+                    .map_err(|e| format!("invalid --expect pattern `{pattern}`: {e}"))?;
+                options.expect = Some(regex);
+            }
+            "--qemu-arg" => options.qemu_args.push(value(&arg, args.next())?),
+            "--headless" => options.headless = true,
+            "--timeout" => {
+                options.timeout = Some(Duration::from_secs(parse_value(&arg, args.next())?))
+            }
+            "-h" | "--help" => return Ok(None),
+            "--" => {
+                options.qemu_args.extend(args);
+                break;
+            }
+            other => return Err(format!("unknown argument `{other}`")),
+        }
+    }
+
+    if options.smp == 0 {
+        return Err("--smp must be at least 1".to_string());
+    }
+
+    Ok(Some(options))
+}
+
+fn value(flag: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{flag} expects a value"))
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = self::value(flag, value)?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value `{value}` for {flag}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Option<Options>, String> {
+        parse(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse_str("").unwrap().unwrap();
+        assert!(matches!(options.mode, Mode::Run));
+        assert_eq!(options.uefi, DEFAULT_UEFI);
+        assert_eq!(options.memory, DEFAULT_MEMORY);
+        assert_eq!(options.smp, DEFAULT_SMP);
+        assert!(!options.kvm);
+    }
+
+    #[test]
+    fn flags_and_passthrough() {
+        let options = parse_str("bios -m 512M --smp 4 --kvm --qemu-arg -s -- -d int")
+            .unwrap()
+            .unwrap();
+        assert!(!options.uefi);
+        assert_eq!(options.memory, "512M");
+        assert_eq!(options.smp, 4);
+        assert!(options.kvm);
+        assert_eq!(options.qemu_args, ["-s", "-d", "int"]);
+    }
+
+    #[test]
+    fn firmware_is_case_insensitive() {
+        assert!(parse_str("UEFI").unwrap().unwrap().uefi);
+        assert!(!parse_str("Bios").unwrap().unwrap().uefi);
+    }
+
+    #[test]
+    fn test_mode_takes_kernel_path() {
+        let options = parse_str("test target/kernel-1234 --timeout 5")
+            .unwrap()
+            .unwrap();
+        assert!(
+            matches!(options.mode, Mode::Test(ref p) if p.to_str() == Some("target/kernel-1234"))
+        );
+        assert_eq!(options.timeout, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse_str("--smp").is_err());
+        assert!(parse_str("--smp zero").is_err());
+        assert!(parse_str("--smp 0").is_err());
+        assert!(parse_str("--bogus").is_err());
+        assert!(parse_str("--help").unwrap().is_none());
+    }
+}
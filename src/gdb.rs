@@ -0,0 +1,31 @@
+use std::fs;
+use std::path::Path;
+
+// The kernel is loaded at a fixed address, this has to match `kernel::KERNEL_BASE`.
+const KERNEL_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+
+// `-s` makes qemu listen for gdb on this port
+const GDB_PORT: u16 = 1234;
+
+/// Returns a gdb script that loads the kernel's symbols at its load address and connects to qemu.
+pub fn script(kernel: &Path) -> String {
+    format!(
+        "\
+# generated by the os runner, start gdb with `gdb -x <this file>`
+set architecture i386:x86-64
+add-symbol-file {kernel} -o {KERNEL_BASE:#x}
+target remote localhost:{GDB_PORT}
+# the kernel isn't loaded yet while the firmware runs, so use hardware breakpoints, e.g.
+# hbreak kernel::memory::map_region
+",
+        kernel = kernel.display(),
+    )
+}
+
+pub fn write_script(path: &Path, kernel: &Path) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    fs::write(path, script(kernel))
+        .map_err(|e| format!("failed to write gdb script to {}: {e}", path.display()))
+}
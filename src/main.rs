@@ -1,72 +1,259 @@
-use ovmf_prebuilt::{Arch, FileType, Prebuilt, Source};
+mod cli;
+mod gdb;
+mod ovmf;
+mod serial;
+
+use cli::{Mode, Options};
 use std::env;
-use std::process::{Command, exit};
+use std::fs::File;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio, exit};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// kernel tests should never take this long, a hang is reported as a timeout instead
+const DEFAULT_TEST_TIMEOUT: Duration = Duration::from_secs(300);
 
-const DEFAULT_UEFI: bool = true;
+// `isa-debug-exit` makes qemu exit with `(value << 1) | 1`, where value is the `QemuExitCode`
+// written by the kernel (0x10 for success, 0x11 for failure).
+const QEMU_EXIT_SUCCESS: i32 = (0x10 << 1) | 1;
+const QEMU_EXIT_FAILED: i32 = (0x11 << 1) | 1;
+
+// exit status of this runner when qemu had to be killed after `--timeout`
+const EXIT_TIMEOUT: i32 = 3;
 
 fn main() {
     // read env variables that were set in build script
     let uefi_path = env!("UEFI_PATH");
     let bios_path = env!("BIOS_PATH");
+    let kernel_path = env!("KERNEL_PATH");
 
-    // parse mode from CLI
-    let args: Vec<String> = env::args().collect();
-    let prog = &args[0];
-
-    // choose whether to start the UEFI or BIOS image
-    let uefi = match args.get(1).map(|s| s.to_lowercase()) {
-        Some(ref s) if s == "uefi" => true,
-        Some(ref s) if s == "bios" => false,
-        Some(ref s) if s == "-h" || s == "--help" => {
-            println!("Usage: {prog} [uefi|bios]");
-            println!("  uefi  - boot using OVMF (UEFI)");
-            println!("  bios  - boot using legacy BIOS");
+    let mut args = env::args();
+    let prog = args.next().unwrap_or_else(|| "os".to_string());
+    let options = match cli::parse(args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", cli::usage(&prog));
             exit(0);
         }
-        _ => {
-            DEFAULT_UEFI
+        Err(e) => {
+            eprintln!("{e}, see {prog} --help");
+            exit(2);
         }
     };
 
-    let mut cmd = Command::new("qemu-system-x86_64");
+    // `cargo test` for the kernel target uses this binary as its runner (see .cargo/config.toml)
+    if let Mode::Test(kernel) = &options.mode {
+        exit(run_test(kernel, &options));
+    }
+
+    let mut cmd = qemu_command(&options);
     // print serial output to the shell
     cmd.arg("-serial").arg("mon:stdio");
-    // enable the guest to exit qemu
-    cmd.arg("-device")
-        .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
-    // cmd.arg("-enable-kvm");
-    cmd.arg("-cpu").arg("qemu64");
-    cmd.arg("-machine").arg("q35");
-    cmd.arg("-m").arg("8G");
-
-    if uefi {
-        let prebuilt =
-            Prebuilt::fetch(Source::LATEST, "target/ovmf").expect("failed to update prebuilt");
 
-        let code = prebuilt.get_file(Arch::X64, FileType::Code);
-        let vars = prebuilt.get_file(Arch::X64, FileType::Vars);
+    if options.uefi {
+        let firmware = ovmf::locate(options.ovmf_code.clone(), options.ovmf_vars.clone())
+            .unwrap_or_else(|e| {
+                eprintln!("{e}");
+                exit(2);
+            });
 
         cmd.arg("-drive")
             .arg(format!("format=raw,file={uefi_path}"));
         cmd.arg("-drive").arg(format!(
             "if=pflash,format=raw,unit=0,file={},readonly=on",
-            code.display()
-        ));
-        // copy vars and enable rw instead of snapshot if you want to store data (e.g. enroll secure boot keys)
-        cmd.arg("-drive").arg(format!(
-            "if=pflash,format=raw,unit=1,file={},snapshot=on",
-            vars.display()
+            firmware.code.display()
         ));
+        if options.persist_vars {
+            // a writable copy so data (e.g. enrolled secure boot keys) survives between runs
+            let vars = ovmf::persistent_vars(&firmware.vars).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                exit(2);
+            });
+            cmd.arg("-drive").arg(format!(
+                "if=pflash,format=raw,unit=1,file={}",
+                vars.display()
+            ));
+        } else {
+            cmd.arg("-drive").arg(format!(
+                "if=pflash,format=raw,unit=1,file={},snapshot=on",
+                firmware.vars.display()
+            ));
+        }
     } else {
         cmd.arg("-drive")
             .arg(format!("format=raw,file={bios_path}"));
     }
 
-    let mut child = cmd.spawn().expect("failed to start qemu-system-x86_64");
-    let status = child.wait().expect("failed to wait on qemu");
-    match status.code().unwrap_or(1) {
-        0x10 => 0,  // success
-        0x11 => 1,  // failure
-        _    => 2,  // unknown fault
+    if let Mode::Debug = options.mode {
+        if let Err(e) = gdb::write_script(&options.gdb_script, Path::new(kernel_path)) {
+            eprintln!("{e}");
+            exit(2);
+        }
+        println!(
+            "qemu is waiting for gdb, attach with `gdb -x {}`",
+            options.gdb_script.display()
+        );
+        // gdb stub on localhost:1234, and don't start the CPU until gdb continues it
+        cmd.arg("-s").arg("-S");
+    }
+    cmd.args(&options.qemu_args);
+
+    let code = match run_qemu(cmd, &options, options.timeout) {
+        Outcome::Expected => 0,
+        Outcome::TimedOut => EXIT_TIMEOUT,
+        Outcome::Exited(status) if options.expect.is_some() => {
+            eprintln!("qemu exited unsuccessfully or before a line matching --expect was printed");
+            exit_code(status).max(1)
+        }
+        // qemu was closed normally (e.g. through the monitor) instead of by the kernel
+        Outcome::Exited(status) if status.success() => 0,
+        Outcome::Exited(status) => exit_code(status),
     };
+    exit(code);
+}
+
+/// Builds the qemu invocation shared by every mode, without any drives attached.
+fn qemu_command(options: &Options) -> Command {
+    let mut cmd = Command::new("qemu-system-x86_64");
+    if options.headless {
+        cmd.arg("-display").arg("none");
+    }
+    // enable the guest to exit qemu
+    cmd.arg("-device")
+        .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+    if options.kvm {
+        cmd.arg("-enable-kvm");
+    }
+    cmd.arg("-cpu").arg(&options.cpu);
+    cmd.arg("-machine").arg(&options.machine);
+    cmd.arg("-m").arg(&options.memory);
+    cmd.arg("-smp").arg(options.smp.to_string());
+    cmd
+}
+
+fn run_test(kernel: &Path, options: &Options) -> i32 {
+    let image = kernel.with_extension("bios.img");
+    bootloader::BiosBoot::new(kernel)
+        .create_disk_image(&image)
+        .expect("failed to create test disk image");
+
+    let mut cmd = qemu_command(options);
+    // tests always run headless
+    if !options.headless {
+        cmd.arg("-display").arg("none");
+    }
+    cmd.arg("-serial").arg("stdio");
+    // a triple fault should end the test instead of rebooting into it again
+    cmd.arg("-no-reboot");
+    cmd.arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()));
+    cmd.args(&options.qemu_args);
+
+    match run_qemu(cmd, options, options.timeout.or(Some(DEFAULT_TEST_TIMEOUT))) {
+        Outcome::Exited(status) => exit_code(status),
+        Outcome::Expected => 0,
+        Outcome::TimedOut => EXIT_TIMEOUT,
+    }
+}
+
+/// How a qemu run ended.
+enum Outcome {
+    Exited(ExitStatus),
+    /// qemu was killed after the timeout passed.
+    TimedOut,
+    /// qemu was killed after the `--expect` pattern was seen in the serial output.
+    Expected,
+}
+
+/// Starts qemu and waits for it to finish.
+///
+/// The serial output is captured through a pipe if a log file or `--expect` pattern was given,
+/// otherwise qemu writes to the terminal directly.
+fn run_qemu(mut cmd: Command, options: &Options, timeout: Option<Duration>) -> Outcome {
+    let matched = Arc::new(AtomicBool::new(false));
+    let capture = if options.log.is_some() || options.expect.is_some() {
+        let log = options.log.as_ref().map(|path| {
+            File::create(path).unwrap_or_else(|e| {
+                eprintln!("failed to create log file {}: {e}", path.display());
+                exit(2);
+            })
+        });
+        cmd.stdout(Stdio::piped());
+        Some(serial::Capture {
+            log,
+            strip_ansi: options.strip_ansi,
+            expect: options.expect.clone(),
+            matched: matched.clone(),
+        })
+    } else {
+        None
+    };
+
+    let mut child = cmd.spawn().expect("failed to start qemu-system-x86_64");
+    let reader = capture.map(|capture| {
+        let stdout = child.stdout.take().expect("qemu stdout is piped");
+        thread::spawn(move || capture.run(stdout))
+    });
+
+    let outcome = wait(&mut child, timeout, &matched);
+
+    if let Some(reader) = reader {
+        match reader.join() {
+            Ok(Err(e)) => eprintln!("failed to capture serial output: {e}"),
+            Err(_) => eprintln!("serial capture thread panicked"),
+            Ok(Ok(())) => {}
+        }
+    }
+
+    // the pattern may have been printed right before qemu exited, which only counts if the
+    // kernel didn't report a failure
+    match outcome {
+        Outcome::Exited(status) if matched.load(Ordering::SeqCst) && succeeded(status) => {
+            Outcome::Expected
+        }
+        outcome => outcome,
+    }
+}
+
+/// Waits for qemu to exit, killing it once `timeout` has passed or `matched` is set.
+fn wait(child: &mut Child, timeout: Option<Duration>, matched: &AtomicBool) -> Outcome {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().expect("failed to wait on qemu") {
+            return Outcome::Exited(status);
+        }
+
+        if matched.load(Ordering::SeqCst) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Outcome::Expected;
+        }
+
+        if let Some(timeout) = timeout
+            && start.elapsed() >= timeout
+        {
+            eprintln!("qemu timed out after {}s, killing it", timeout.as_secs());
+            let _ = child.kill();
+            let _ = child.wait();
+            return Outcome::TimedOut;
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Whether qemu was closed normally or the kernel exited with `QemuExitCode::Success`.
+fn succeeded(status: ExitStatus) -> bool {
+    status.success() || exit_code(status) == 0
+}
+
+fn exit_code(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(QEMU_EXIT_SUCCESS) => 0,
+        Some(QEMU_EXIT_FAILED) => 1,
+        _ => 2, // unknown fault
+    }
 }
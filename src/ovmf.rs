@@ -0,0 +1,106 @@
+use ovmf_prebuilt::{Arch, FileType, Prebuilt, Source};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PREBUILT_DIR: &str = "target/ovmf";
+// writable copy of the VARS file used with `--persist-vars`
+const PERSISTENT_VARS: &str = "target/ovmf-vars.fd";
+
+// (code, vars) pairs as installed by the common distribution packages
+const SYSTEM_LOCATIONS: &[(&str, &str)] = &[
+    // Debian / Ubuntu
+    (
+        "/usr/share/OVMF/OVMF_CODE_4M.fd",
+        "/usr/share/OVMF/OVMF_VARS_4M.fd",
+    ),
+    (
+        "/usr/share/OVMF/OVMF_CODE.fd",
+        "/usr/share/OVMF/OVMF_VARS.fd",
+    ),
+    // Fedora
+    (
+        "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+        "/usr/share/edk2/ovmf/OVMF_VARS.fd",
+    ),
+    // Arch
+    (
+        "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+        "/usr/share/edk2/x64/OVMF_VARS.4m.fd",
+    ),
+    (
+        "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+        "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd",
+    ),
+];
+
+pub struct Firmware {
+    pub code: PathBuf,
+    pub vars: PathBuf,
+}
+
+/// Finds the OVMF firmware to boot with.
+///
+/// Explicit paths (`--ovmf-code`/`--ovmf-vars`, then `OVMF_CODE`/`OVMF_VARS`) take priority,
+/// followed by the system locations above. The prebuilt firmware is only fetched (which needs
+/// network access the first time) if none of these exist.
+pub fn locate(code: Option<PathBuf>, vars: Option<PathBuf>) -> Result<Firmware, String> {
+    let code = code.or_else(|| env::var_os("OVMF_CODE").map(PathBuf::from));
+    let vars = vars.or_else(|| env::var_os("OVMF_VARS").map(PathBuf::from));
+
+    match (code, vars) {
+        (Some(code), Some(vars)) => {
+            for path in [&code, &vars] {
+                if !path.is_file() {
+                    return Err(format!(
+                        "OVMF firmware file {} does not exist",
+                        path.display()
+                    ));
+                }
+            }
+            return Ok(Firmware { code, vars });
+        }
+        (None, None) => {}
+        _ => return Err("OVMF CODE and VARS paths must be given together".to_string()),
+    }
+
+    if let Some((code, vars)) = SYSTEM_LOCATIONS
+        .iter()
+        .find(|(code, vars)| Path::new(code).is_file() && Path::new(vars).is_file())
+    {
+        return Ok(Firmware {
+            code: PathBuf::from(code),
+            vars: PathBuf::from(vars),
+        });
+    }
+
+    let prebuilt = Prebuilt::fetch(Source::LATEST, PREBUILT_DIR).map_err(|e| {
+        format!(
+            "no local OVMF firmware found and fetching the prebuilt firmware failed ({e}). \
+             Install OVMF, pass --ovmf-code/--ovmf-vars or set OVMF_CODE/OVMF_VARS"
+        )
+    })?;
+
+    Ok(Firmware {
+        code: prebuilt.get_file(Arch::X64, FileType::Code),
+        vars: prebuilt.get_file(Arch::X64, FileType::Vars),
+    })
+}
+
+/// Returns a writable copy of `vars` that is kept between runs, creating it on first use.
+pub fn persistent_vars(vars: &Path) -> Result<PathBuf, String> {
+    let path = PathBuf::from(PERSISTENT_VARS);
+    if !path.exists() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        fs::copy(vars, &path).map_err(|e| {
+            format!(
+                "failed to copy {} to {}: {e}",
+                vars.display(),
+                path.display()
+            )
+        })?;
+    }
+    Ok(path)
+}
@@ -0,0 +1,114 @@
+use regex::Regex;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Where qemu's serial output goes besides the terminal.
+pub struct Capture {
+    pub log: Option<File>,
+    pub strip_ansi: bool,
+    pub expect: Option<Regex>,
+    /// Set once a line matching `expect` was seen.
+    pub matched: Arc<AtomicBool>,
+}
+
+impl Capture {
+    /// Copies `source` to stdout until it is closed, writing complete lines to the log and
+    /// matching them against `expect`.
+    pub fn run(mut self, mut source: impl Read) -> io::Result<()> {
+        let mut log = self.log.take().map(BufWriter::new);
+        let mut stdout = io::stdout();
+        let mut line = Vec::new();
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let n = source.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            // partial lines (like the timer's dots) should show up immediately
+            stdout.write_all(&buf[..n])?;
+            stdout.flush()?;
+
+            for &byte in &buf[..n] {
+                line.push(byte);
+                if byte == b'\n' {
+                    self.line(&line, log.as_mut())?;
+                    line.clear();
+                }
+            }
+        }
+
+        if !line.is_empty() {
+            self.line(&line, log.as_mut())?;
+        }
+        if let Some(log) = log.as_mut() {
+            log.flush()?;
+        }
+        Ok(())
+    }
+
+    fn line(&self, line: &[u8], log: Option<&mut BufWriter<File>>) -> io::Result<()> {
+        let line = String::from_utf8_lossy(line);
+        let stripped = strip_ansi(&line);
+
+        if let Some(log) = log {
+            if self.strip_ansi {
+                log.write_all(stripped.as_bytes())?;
+            } else {
+                log.write_all(line.as_bytes())?;
+            }
+        }
+
+        if let Some(expect) = &self.expect
+            && expect.is_match(stripped.trim_end())
+        {
+            self.matched.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+/// Removes ANSI escape sequences (like the logger's colors) from `line`.
+pub fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+
+        // CSI: parameter and intermediate bytes, terminated by a byte in 0x40..=0x7E.
+        // Any other escape is a single character which was consumed by the comparison.
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_ansi;
+
+    #[test]
+    fn strips_logger_colors() {
+        let line = "\x1b[0;2;37m[\x1b[0;92m\x1b[1mINFO \x1b[0;2;37m]\x1b[0;1;97m kernel \x1b[0;2;37m>\x1b[0;97m Kernel initialized\x1b[0m\n";
+        assert_eq!(strip_ansi(line), "[INFO ] kernel > Kernel initialized\n");
+    }
+
+    #[test]
+    fn strips_clear_screen() {
+        assert_eq!(strip_ansi("\x1b[H\x1b[2Jhello"), "hello");
+        assert_eq!(strip_ansi("plain"), "plain");
+    }
+}